@@ -0,0 +1,98 @@
+use std::io::{self, Write};
+
+/// Writer that frames everything written to it with HTTP/1.1 chunked transfer encoding
+///
+/// Used for responses where the length isn't known up front
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Wrap a writer, the `Transfer-Encoding: chunked` header must already have been sent
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Write the terminating zero-length chunk, and give back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body, so don't send it
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // chunk size in hex, then the data
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::ChunkedWriter;
+
+    #[test]
+    fn frames_each_write_as_a_chunk() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"data: initial\n\n").unwrap();
+        writer.write_all(b"hello world, this is longer").unwrap();
+
+        assert_eq!(
+            writer.finish().unwrap(),
+            b"f\r\ndata: initial\n\n\r\n1b\r\nhello world, this is longer\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn empty_writes_dont_end_the_body() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"").unwrap();
+        writer.write_all(b"a").unwrap();
+
+        assert_eq!(writer.finish().unwrap(), b"1\r\na\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn decodes_to_the_written_body() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        for part in ["first ", "second ", "third"] {
+            writer.write_all(part.as_bytes()).unwrap();
+        }
+        let encoded = writer.finish().unwrap();
+
+        // decode the chunks again
+        let mut body = Vec::new();
+        let mut rest = &encoded[..];
+        loop {
+            let line_end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size = std::str::from_utf8(&rest[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            rest = &rest[line_end + 2..];
+            if size == 0 {
+                assert_eq!(rest, b"\r\n");
+                break;
+            }
+            body.extend_from_slice(&rest[..size]);
+            assert_eq!(&rest[size..size + 2], b"\r\n");
+            rest = &rest[size + 2..];
+        }
+
+        assert_eq!(body, b"first second third");
+    }
+}
@@ -0,0 +1,113 @@
+use std::{net::IpAddr, str::FromStr};
+
+/// Range of ip addresses, like `192.168.0.0/16`, or a single address
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    /// Whether the address is in the range
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        // ipv4 clients on an ipv6 socket show up as mapped addresses
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(range) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(range) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("invalid address: {e}"))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or(format!("invalid prefix length: {prefix}"))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::Cidr;
+
+    fn contains(range: &str, addr: &str) -> bool {
+        let range = range.parse::<Cidr>().unwrap();
+        range.contains(&addr.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        assert!(contains("192.168.0.0/16", "192.168.0.1"));
+        assert!(contains("192.168.0.0/16", "192.168.255.255"));
+        assert!(!contains("192.168.0.0/16", "192.169.0.1"));
+        assert!(!contains("192.168.0.0/16", "10.0.0.1"));
+        assert!(contains("10.1.2.3/8", "10.200.0.1"));
+    }
+
+    #[test]
+    fn single_addresses() {
+        assert!(contains("127.0.0.1", "127.0.0.1"));
+        assert!(!contains("127.0.0.1", "127.0.0.2"));
+        assert!(contains("::1", "::1"));
+        assert!(!contains("::1", "::2"));
+    }
+
+    #[test]
+    fn zero_prefix_matches_everything_of_its_family() {
+        assert!(contains("0.0.0.0/0", "1.2.3.4"));
+        assert!(contains("0.0.0.0/0", "255.255.255.255"));
+        assert!(!contains("0.0.0.0/0", "::1"));
+        assert!(contains("::/0", "2001:db8::1"));
+        assert!(!contains("::/0", "1.2.3.4"));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        assert!(contains("2001:db8::/32", "2001:db8:1234::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("fe80::/10", "febf::1"));
+        assert!(!contains("fe80::/10", "fec0::1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_match_ipv4_ranges() {
+        assert!(contains("192.168.0.0/16", "::ffff:192.168.1.1"));
+        assert!(!contains("192.168.0.0/16", "::ffff:10.0.0.1"));
+        assert!(contains("127.0.0.1", "::ffff:127.0.0.1"));
+    }
+
+    #[test]
+    fn invalid_ranges_are_refused() {
+        assert!("192.168.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("192.168.0.0/".parse::<Cidr>().is_err());
+        assert!("not an address".parse::<Cidr>().is_err());
+    }
+}
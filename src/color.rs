@@ -0,0 +1,82 @@
+use std::{
+    env,
+    fmt::Display,
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::ValueEnum;
+
+/// Whether output is colored, set once at startup
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// When to color terminal output
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorMode {
+    /// Only when writing to a terminal, and NO_COLOR isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+/// Decide whether to color output from now on
+pub fn init(mode: ColorMode) {
+    let enabled = match mode {
+        ColorMode::Auto => {
+            io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Text in green, for the server starting
+pub fn green(text: impl Display) -> String {
+    paint("32", text)
+}
+
+/// Text in yellow, for reloads and warnings
+pub fn yellow(text: impl Display) -> String {
+    paint("33", text)
+}
+
+/// Text in red, for errors
+pub fn red(text: impl Display) -> String {
+    paint("31", text)
+}
+
+/// Wrap the text in the ansi color code, if coloring is on
+fn paint(code: &str, text: impl Display) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{code}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Print an error line, like println, in red if coloring is on
+macro_rules! print_error {
+    ($($arg:tt)*) => {
+        println!("{}", $crate::color::red(format_args!("[ERR] {}", format_args!($($arg)*))))
+    };
+}
+pub(crate) use print_error;
+
+#[cfg(test)]
+mod tests {
+    use super::{green, init, red, yellow, ColorMode};
+
+    #[test]
+    fn never_has_no_escape_codes() {
+        // the only test that turns coloring on, it ends with it off again
+        init(ColorMode::Always);
+        assert_eq!(green("up"), "\x1b[32mup\x1b[0m");
+
+        init(ColorMode::Never);
+        for text in [green("up"), yellow("reload"), red("error")] {
+            assert!(!text.contains('\x1b'), "{text:?}");
+        }
+        assert_eq!(yellow("reload"), "reload");
+    }
+}
@@ -0,0 +1,70 @@
+/// Whether the text matches a glob pattern, where `*` matches any run of characters and `?` any one character
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    // position to go back to after the last *, to let it match one more character
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    // only stars can match the empty rest
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn mime_patterns() {
+        assert!(matches("image/*", "image/png"));
+        assert!(matches("*/*", "text/html"));
+        assert!(matches("text/html", "text/html"));
+        assert!(!matches("image/*", "text/plain"));
+        assert!(!matches("text/htm", "text/html"));
+    }
+
+    #[test]
+    fn stars_and_question_marks() {
+        assert!(matches("*", ""));
+        assert!(matches("**", "anything"));
+        assert!(matches("a*b*c", "aXXbYYc"));
+        assert!(matches("a*c", "abcbc"));
+        assert!(!matches("a*c", "abcb"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", ".txt"));
+        assert!(!matches("?", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn path_patterns_cross_directories() {
+        assert!(matches("*.map", "app.js.map"));
+        assert!(matches("*.map", "dist/js/app.js.map"));
+        assert!(matches("drafts/*", "drafts/post.md"));
+        assert!(matches("drafts/*", "drafts/2024/post.md"));
+        assert!(!matches("drafts/*", "posts/drafts.md"));
+        assert!(matches("*/cache/*", "a/b/cache/c/d"));
+    }
+}
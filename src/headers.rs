@@ -0,0 +1,133 @@
+use std::{fs, path::Path};
+
+use crate::glob;
+
+/// Headers to send for the paths matching a pattern
+pub struct Rule {
+    /// Pattern for the path, like /assets/*
+    pub pattern: String,
+
+    /// Headers to send, in the order they were given
+    pub headers: Vec<(String, String)>,
+}
+
+/// Read the rules from a `_headers` file, there are none if it doesn't exist
+pub fn read_rules(file: &Path) -> Vec<Rule> {
+    parse_rules(&fs::read_to_string(file).unwrap_or_default())
+}
+
+/// Parse the rules in a `_headers` file
+///
+/// A line that isn't indented starts a rule with its path pattern,
+/// and the indented `Name: Value` lines after it are its headers.
+/// Lines starting with `#` are comments
+fn parse_rules(text: &str) -> Vec<Rule> {
+    let mut rules = Vec::<Rule>::new();
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        // headers belong to the rule above them
+        if line.starts_with(char::is_whitespace) {
+            let (Some(rule), Some((name, value))) = (rules.last_mut(), trimmed.split_once(':'))
+            else {
+                continue;
+            };
+            rule.headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        } else {
+            rules.push(Rule {
+                pattern: trimmed.to_string(),
+                headers: Vec::new(),
+            });
+        }
+    }
+
+    rules
+}
+
+/// Headers of all rules matching the path, in the order of the file
+pub fn for_path<'a>(
+    rules: &'a [Rule],
+    path: &'a str,
+) -> impl Iterator<Item = &'a (String, String)> {
+    rules
+        .iter()
+        .filter(move |rule| glob::matches(&rule.pattern, path))
+        .flat_map(|rule| &rule.headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{for_path, parse_rules};
+
+    const RULES: &str = "\
+# comments and empty lines are skipped
+
+/*
+  X-Frame-Options: DENY
+/assets/*
+  Cache-Control: max-age=31536000
+  X-Asset:   yes  
+/*.html
+\tCache-Control: no-store
+  not a header
+";
+
+    fn headers_for(path: &str) -> Vec<(String, String)> {
+        for_path(&parse_rules(RULES), path).cloned().collect()
+    }
+
+    fn header(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn parses_patterns_and_their_headers() {
+        let rules = parse_rules(RULES);
+        let patterns = rules
+            .iter()
+            .map(|rule| rule.pattern.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(patterns, ["/*", "/assets/*", "/*.html"]);
+        assert_eq!(
+            rules[1].headers,
+            [
+                header("Cache-Control", "max-age=31536000"),
+                header("X-Asset", "yes")
+            ]
+        );
+        assert_eq!(rules[2].headers, [header("Cache-Control", "no-store")]);
+    }
+
+    #[test]
+    fn applies_every_matching_rule_in_order() {
+        assert_eq!(
+            headers_for("/assets/app.js"),
+            [
+                header("X-Frame-Options", "DENY"),
+                header("Cache-Control", "max-age=31536000"),
+                header("X-Asset", "yes")
+            ]
+        );
+        assert_eq!(
+            headers_for("/docs/page.html"),
+            [
+                header("X-Frame-Options", "DENY"),
+                header("Cache-Control", "no-store")
+            ]
+        );
+        assert_eq!(headers_for("/"), [header("X-Frame-Options", "DENY")]);
+    }
+
+    #[test]
+    fn headers_before_any_pattern_are_skipped() {
+        let rules = parse_rules("  X-Lost: yes\n/a\n  X-Kept: yes\n");
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].headers, [header("X-Kept", "yes")]);
+        assert!(for_path(&rules, "/b").next().is_none());
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fs, io, path::Path, time::UNIX_EPOCH};
+
+/// File or directory in a directory listing
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+
+    /// Seconds since the unix epoch
+    pub modified: u64,
+}
+
+/// Read the entries of a directory, directories first and then sorted by name
+///
+/// Links are listed as what they point to, broken ones are left out
+pub fn read_entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = fs::metadata(entry.path()).ok()?;
+            Some(Entry {
+                name: entry.file_name().into_string().ok()?,
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map(|time| time.as_secs())
+                    .unwrap_or(0),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// Paths of the files under the root, sorted and relative to it, leaving out names starting with a dot
+///
+/// Walking stops once `max` files are found, so huge trees aren't read in full.
+/// Paths that `keep` refuses are left out, and linked directories aren't entered so links can't loop
+pub fn all_files(root: &Path, max: usize, keep: &dyn Fn(&Path) -> bool) -> Vec<String> {
+    let mut files = Vec::new();
+    walk_files(root, "", max, keep, &mut files);
+    files
+}
+
+/// Add the files in a directory and below it, until there are `max`
+fn walk_files(
+    dir: &Path,
+    prefix: &str,
+    max: usize,
+    keep: &dyn Fn(&Path) -> bool,
+    files: &mut Vec<String>,
+) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries = entries.filter_map(|entry| entry.ok()).collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        if files.len() >= max {
+            return;
+        }
+
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let path = entry.path();
+        if name.starts_with('.') || !keep(&path) {
+            continue;
+        }
+
+        let is_link = entry.file_type().is_ok_and(|kind| kind.is_symlink());
+        if path.is_dir() && !is_link {
+            walk_files(&path, &format!("{prefix}{name}/"), max, keep, files);
+        } else if path.is_file() {
+            files.push(format!("{prefix}{name}"));
+        }
+    }
+}
+
+/// Html page listing the entries, url is the path the directory is served at
+pub fn html_listing(url: &str, entries: &[Entry]) -> Vec<u8> {
+    let url = url.trim_end_matches('/');
+    let href = url
+        .split('/')
+        .map(percent_encode)
+        .collect::<Vec<_>>()
+        .join("/");
+    let mut page = format!(
+        "<!DOCTYPE html><h1>Index of {}/</h1><ul><li><a href=\"{href}/..\">..</a></li>",
+        html_escape(url),
+    );
+
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        page.push_str(&format!(
+            "<li><a href=\"{href}/{}{slash}\">{}{slash}</a></li>",
+            percent_encode(&entry.name),
+            html_escape(&entry.name),
+        ));
+    }
+
+    page.push_str("</ul>");
+    page.into_bytes()
+}
+
+/// Json array listing the entries
+pub fn json_listing(entries: &[Entry]) -> Vec<u8> {
+    let entries = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"name\":{},\"is_dir\":{},\"size\":{},\"modified\":{}}}",
+                json_string(&entry.name),
+                entry.is_dir,
+                entry.size,
+                entry.modified
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("[{}]", entries.join(",")).into_bytes()
+}
+
+/// Quote and escape a string for json
+pub fn json_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Percent encode a path segment for use in a url, so names with `#`, `?` or `%` link to themselves
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+/// Escape a string for use in html
+pub fn html_escape(string: &str) -> String {
+    string
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{html_listing, percent_encode, read_entries, Entry};
+
+    fn entry(name: &str, is_dir: bool) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size: 0,
+            modified: 0,
+        }
+    }
+
+    #[test]
+    fn encodes_reserved_characters() {
+        assert_eq!(percent_encode("a#b.txt"), "a%23b.txt");
+        assert_eq!(percent_encode("what?.txt"), "what%3F.txt");
+        assert_eq!(percent_encode("100%.txt"), "100%25.txt");
+        assert_eq!(percent_encode("with space"), "with%20space");
+        assert_eq!(percent_encode("café"), "caf%C3%A9");
+        assert_eq!(percent_encode("plain-name_1.0~"), "plain-name_1.0~");
+    }
+
+    #[test]
+    fn links_are_encoded_and_names_escaped() {
+        let page = html_listing("/my docs/", &[entry("a#b", true), entry("<x>&y", false)]);
+        let page = String::from_utf8(page).unwrap();
+
+        assert!(page.contains("<h1>Index of /my docs/</h1>"));
+        assert!(page.contains("<a href=\"/my%20docs/..\">..</a>"));
+        assert!(page.contains("<a href=\"/my%20docs/a%23b/\">a#b/</a>"));
+        assert!(page.contains("<a href=\"/my%20docs/%3Cx%3E%26y\">&lt;x&gt;&amp;y</a>"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn linked_directories_are_listed_as_directories() {
+        let dir = std::env::temp_dir().join("http-reloaded-test-listing-links");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("file.txt"), "hi").unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("linked")).unwrap();
+        std::os::unix::fs::symlink(dir.join("gone"), dir.join("broken")).unwrap();
+
+        let entries = read_entries(&dir).unwrap();
+        let listed = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.is_dir))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            [("linked", true), ("real", true), ("file.txt", false)]
+        );
+    }
+}
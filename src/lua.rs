@@ -0,0 +1,241 @@
+use std::{
+    collections::hash_map::RandomState,
+    env, fs,
+    hash::{BuildHasher, Hasher},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use mlua::{Lua, LuaString, Table};
+
+/// Find the lua script that builds the site, if the path is one or a directory containing `site.lua`
+pub fn find_site_script(path: &Path) -> Option<PathBuf> {
+    if path.is_file() && path.extension().is_some_and(|ext| ext == "lua") {
+        Some(path.to_path_buf())
+    } else if path.join("site.lua").is_file() {
+        Some(path.join("site.lua"))
+    } else {
+        None
+    }
+}
+
+/// Build the site by running the lua script, and write the output to the output directory
+///
+/// The script returns a table of output file paths and their content.
+/// It can use `read(path)` to read a file, and `list(path)` to list the names in a directory,
+/// both relative to the directory the script is in.
+/// If clean is set, everything that was in the output directory is removed first
+pub fn build_site(script: &Path, out: &Path, clean: bool) -> Result<(), anyhow::Error> {
+    let root = script_root(script);
+    let lua = Lua::new();
+
+    // read a file
+    let read_root = root.clone();
+    lua.globals().set(
+        "read",
+        lua.create_function(move |lua, path: String| {
+            let content = fs::read(read_root.join(path)).map_err(mlua::Error::external)?;
+            lua.create_string(content)
+        })?,
+    )?;
+
+    // list a directory
+    let list_root = root.clone();
+    lua.globals().set(
+        "list",
+        lua.create_function(move |_, path: String| {
+            let mut names = fs::read_dir(list_root.join(path))
+                .map_err(mlua::Error::external)?
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .collect::<Vec<_>>();
+            names.sort();
+            Ok(names)
+        })?,
+    )?;
+
+    // run the script
+    let source = fs::read_to_string(script)?;
+    let pages: Table = lua
+        .load(source)
+        .set_name(format!("@{}", script.display()))
+        .eval()?;
+
+    // write out the pages
+    if clean && out.exists() {
+        check_clean_out(script, out)?;
+        fs::remove_dir_all(out)?;
+    }
+    fs::create_dir_all(out)?;
+
+    for pair in pages.pairs::<String, LuaString>() {
+        let (path, content) = pair?;
+        let dest = out.join(path.trim_start_matches('/'));
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(dest, content.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Check that cleaning the output directory can't remove the site itself
+///
+/// The output can't be the directory the script is in, or a directory above it
+pub fn check_clean_out(script: &Path, out: &Path) -> Result<(), anyhow::Error> {
+    let resolve = |path: &Path| fs::canonicalize(path).or_else(|_| std::path::absolute(path));
+    let root = resolve(&script_root(script))?;
+    let out = resolve(out)?;
+    if root.starts_with(&out) {
+        anyhow::bail!(
+            "Cleaning {} would remove the site in {}, use another --out or leave out --clean-out",
+            out.display(),
+            root.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Make a new, randomly named directory to build into when there's no --out, only accessible to us
+pub fn temp_out() -> Result<PathBuf, std::io::Error> {
+    loop {
+        let name = RandomState::new().build_hasher().finish();
+        let dir = env::temp_dir().join(format!("http-reloaded-{name:016x}"));
+
+        // never reuse a directory that's already there
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        match builder.create(&dir) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            result => return result.map(|_| dir),
+        }
+    }
+}
+
+/// Directory the script is in, which its paths are relative to
+fn script_root(script: &Path) -> PathBuf {
+    script
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::{build_site, check_clean_out, find_site_script, temp_out};
+
+    /// Directory with a site script and a file next to it, cleared again by the next run
+    fn site(name: &str, script: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("http-reloaded-test-lua-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("site.lua"), script).unwrap();
+        fs::write(dir.join("notes.txt"), "notes").unwrap();
+        dir
+    }
+
+    #[test]
+    fn cleaning_the_site_itself_is_refused() {
+        let dir = site("clean-out", "return { [\"index.html\"] = \"hi\" }");
+        let script = dir.join("site.lua");
+
+        assert!(check_clean_out(&script, &dir).is_err());
+        assert!(check_clean_out(&script, &dir.join(".")).is_err());
+        assert!(check_clean_out(&script, dir.parent().unwrap()).is_err());
+        assert!(check_clean_out(&script, &dir.join("dist")).is_ok());
+
+        // and building doesn't remove anything either
+        assert!(build_site(&script, &dir, true).is_err());
+        assert!(dir.join("notes.txt").is_file());
+        assert!(dir.join("site.lua").is_file());
+
+        build_site(&script, &dir.join("dist"), true).unwrap();
+        build_site(&script, &dir.join("dist"), true).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("dist/index.html")).unwrap(),
+            "hi"
+        );
+    }
+
+    #[test]
+    fn temporary_output_is_new_every_time() {
+        let first = temp_out().unwrap();
+        let second = temp_out().unwrap();
+        assert_ne!(first, second);
+        assert!(first.is_dir() && second.is_dir());
+        assert_eq!(fs::read_dir(&first).unwrap().count(), 0);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&first).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        fs::remove_dir_all(first).unwrap();
+        fs::remove_dir_all(second).unwrap();
+    }
+
+    #[test]
+    fn site_script_is_found() {
+        let dir = site("find", "return {}");
+        fs::write(dir.join("build.lua"), "return {}").unwrap();
+
+        assert_eq!(find_site_script(&dir), Some(dir.join("site.lua")));
+        assert_eq!(
+            find_site_script(&dir.join("build.lua")),
+            Some(dir.join("build.lua"))
+        );
+        assert_eq!(find_site_script(&dir.join("notes.txt")), None);
+
+        fs::remove_file(dir.join("site.lua")).unwrap();
+        assert_eq!(find_site_script(&dir), None);
+    }
+
+    #[test]
+    fn script_reads_and_lists_next_to_itself() {
+        let dir = site(
+            "read-list",
+            r#"
+            return {
+                ["index.html"] = read("notes.txt"),
+                ["files/list.txt"] = table.concat(list("."), ","),
+                ["/deep/er/page.html"] = "deep",
+            }
+            "#,
+        );
+        let out = dir.join("dist");
+        build_site(&dir.join("site.lua"), &out, false).unwrap();
+
+        let read = |path: &str| fs::read_to_string(out.join(path)).unwrap();
+        assert_eq!(read("index.html"), "notes");
+        assert_eq!(read("files/list.txt"), "notes.txt,site.lua");
+        assert_eq!(read("deep/er/page.html"), "deep");
+    }
+
+    #[test]
+    fn failing_script_keeps_the_last_build() {
+        let dir = site("failing", "return { [\"index.html\"] = \"first\" }");
+        let script = dir.join("site.lua");
+        let out = dir.join("dist");
+        build_site(&script, &out, true).unwrap();
+
+        fs::write(
+            &script,
+            "return { [\"index.html\"] = read(\"missing.txt\") }",
+        )
+        .unwrap();
+        assert!(build_site(&script, &out, true).is_err());
+        fs::write(&script, "this is not lua").unwrap();
+        assert!(build_site(&script, &out, true).is_err());
+
+        assert_eq!(fs::read_to_string(out.join("index.html")).unwrap(), "first");
+    }
+}
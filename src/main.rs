@@ -1,242 +1,4543 @@
 use std::{
+    collections::HashSet,
     fs,
-    io::{BufRead, BufReader, Write},
-    net::{TcpListener, TcpStream},
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::Duration,
+    io::{self, ErrorKind, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs},
+    num::NonZeroUsize,
+    path::{Component, Path, PathBuf},
+    process::Command,
+    sync::{atomic::Ordering, mpsc, Arc, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use clap::Parser;
-use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode};
+use cidr::Cidr;
+use clap::{Parser, ValueEnum};
+use color::{print_error, ColorMode};
+use mime::{get_mime_type, is_text_mime_type};
+use notify_debouncer_mini::{
+    new_debouncer, new_debouncer_opt,
+    notify::{self, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher},
+    DebounceEventResult, DebouncedEvent, Debouncer,
+};
+use request::{Request, TooLarge};
+use socket2::{Domain, Protocol, Socket, Type};
+use stats::Stats;
+use update_client::UpdateClient;
+
+mod chunked;
+mod cidr;
+mod color;
+mod glob;
+mod headers;
+mod listing;
+mod lua;
+mod mime;
+mod precompressed;
+mod request;
+mod stats;
+mod update_client;
 
 const RW_ERR: &str = "Cronch: lock was poissoned";
 const VERY_LONG_PATH: &str = "very-long-path-name-intentionally-used-to-get-update-notifications-please-do-not-name-your-files-like-this.rs";
 const UPDATE_NOTIFY_SCRIPT: &str = include_str!("update_notify.html");
+const STATUS_PATH: &str = "__status";
+const CLIENTS_PATH: &str = "__clients";
+
+/// Path to list all served files at
+const FILES_PATH: &str = "__files";
+
+/// Methods that can be used on pages
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// File in the root of the site with header rules for paths
+const HEADERS_FILE: &str = "_headers";
+
+/// Paths the server answers itself, these always win over files with the same name
+const RESERVED_PATHS: [&str; 4] = [VERY_LONG_PATH, STATUS_PATH, CLIENTS_PATH, FILES_PATH];
+const REOPEN_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Workers answering connections, workers spend most time waiting on clients so this is more than the cpus.
+/// Browsers open connections ahead of time that only send a request later, if at all, and each holds a worker
+const DEFAULT_THREADS: usize = 64;
+/// Empty http/2 SETTINGS frame, followed by a GOAWAY frame with HTTP_1_1_REQUIRED as error
+const HTTP2_REJECT: &[u8] = &[
+    0, 0, 0, 4, 0, 0, 0, 0, 0, // SETTINGS
+    0, 0, 8, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xd, // GOAWAY
+];
+const DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+/// State shared between everything handling requests
+struct Server {
+    /// Path to serve
+    path: PathBuf,
+
+    /// Arguments the server was started with
+    args: Args,
+
+    /// Lua script that builds the site into the served path
+    site_script: Option<PathBuf>,
+
+    /// Url to open in the browser
+    browser_url: String,
+
+    /// Script to add to html pages
+    update_notify_script: String,
+
+    /// Streams to notify when an update happens
+    update_notify: Mutex<Vec<UpdateClient>>,
+
+    /// Counters for the status page and shutdown summary
+    stats: Stats,
+
+    /// Header rules from the `_headers` file in the served path
+    header_rules: RwLock<Vec<headers::Rule>>,
+}
+
+/// Debounced watcher for file changes, using the OS or by polling
+enum FileWatcher {
+    Native(Debouncer<RecommendedWatcher>),
+    Poll(Debouncer<PollWatcher>),
+}
+
+impl FileWatcher {
+    /// Start the watcher the arguments ask for, sending the events to the sender
+    fn new(args: &Args, sender: mpsc::Sender<DebounceEventResult>) -> Result<Self, notify::Error> {
+        let timeout = Duration::from_millis(args.debounce);
+        if args.poll {
+            // comparing contents catches changes that don't update the modified time
+            let config = notify_debouncer_mini::Config::default()
+                .with_timeout(timeout)
+                .with_notify_config(
+                    notify::Config::default()
+                        .with_poll_interval(Duration::from_millis(args.poll_interval))
+                        .with_compare_contents(args.poll_compare_contents),
+                );
+            Ok(Self::Poll(new_debouncer_opt(config, sender)?))
+        } else {
+            Ok(Self::Native(new_debouncer(timeout, sender)?))
+        }
+    }
+
+    /// The watcher used
+    fn watcher(&mut self) -> &mut dyn Watcher {
+        match self {
+            Self::Native(debouncer) => debouncer.watcher(),
+            Self::Poll(debouncer) => debouncer.watcher(),
+        }
+    }
+}
+
+impl Server {
+    /// Build the site with the lua script, if there is one
+    fn build_site(&self) -> Result<(), anyhow::Error> {
+        let Some(script) = &self.site_script else {
+            return Ok(());
+        };
+
+        // the temporary directory is ours, so it can always be cleaned
+        let clean = self.args.clean_out || self.args.out.is_none();
+        lua::build_site(script, &self.path, clean)
+    }
+
+    /// Remove the output of the lua script, when it was built into a temporary directory
+    fn remove_temp_out(&self) {
+        if self.site_script.is_some() && self.args.out.is_none() && self.path.exists() {
+            if let Err(e) = fs::remove_dir_all(&self.path) {
+                print_error!("While removing {}: {:?}", self.path.display(), e);
+            }
+        }
+    }
+
+    /// Drop the clients that closed their event stream
+    fn reap_clients(&self) {
+        let mut stream = self.update_notify.lock().expect(RW_ERR);
+        stream.retain_mut(|s| !s.is_closed());
+    }
+
+    /// Read the header rules from the `_headers` file again
+    fn reload_header_rules(&self) {
+        *self.header_rules.write().expect(RW_ERR) =
+            headers::read_rules(&self.path.join(HEADERS_FILE));
+    }
+}
 
 /// Serve the files
-fn serve(path: PathBuf, addr: Option<String>) -> Result<(), anyhow::Error> {
-    // stream to notify when an update happens
-    let update_notify = Arc::new(Mutex::new(Vec::<TcpStream>::new()));
-
-    let update_notify_cloned = update_notify.clone();
-    let mut debouncer = new_debouncer(Duration::from_millis(500), move |res| match res {
-        Ok(_) => {
-            println!("Files changed, reloading");
-
-            // notify the upate
-            let mut stream = update_notify_cloned.lock().expect(RW_ERR);
-            stream.retain_mut(
-                |s| match s.write_all(b"data: update\n\n").and_then(|_| s.flush()) {
-                    Ok(()) => true,
-                    Err(_) => false,
-                },
-            );
+fn serve(args: Args) -> Result<(), anyhow::Error> {
+    let path = args.path.clone().unwrap_or(PathBuf::from("."));
+
+    // directory to watch
+    let watch_path = if path.is_file() {
+        path.parent()
+            .expect("File does not have a parent directory")
+            .to_path_buf()
+    } else {
+        path.clone()
+    };
+    let watch_path = if watch_path.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        watch_path
+    };
+
+    // if there's a lua script, build the site with it and serve the output instead
+    let site_script = lua::find_site_script(&path);
+
+    // cleaning the output must not remove the site itself
+    if let (Some(script), Some(out), true) = (&site_script, &args.out, args.clean_out) {
+        lua::check_clean_out(script, out)?;
+    }
+    // without --out, the site is built into a temporary directory, made once it's needed
+    let path = if site_script.is_some() {
+        args.out.clone()
+    } else {
+        Some(path)
+    };
+
+    // listener that was passed in, it's already bound so it replaces the default address
+    let inherited = args.listen_fd.map(listen_fd).transpose()?;
+
+    // addresses to listen on
+    let addrs = if args.address.is_empty() && inherited.is_none() {
+        vec!["127.0.0.1:1111".to_string()]
+    } else {
+        args.address.clone()
+    };
+
+    // watch the current dir
+    let recursive_mode = if args.watch_root_only {
+        RecursiveMode::NonRecursive
+    } else {
+        RecursiveMode::Recursive
+    };
+
+    // only show what would be done
+    if args.dry_run {
+        let summary = dry_run_summary(
+            &args,
+            path.as_deref(),
+            &watch_path,
+            recursive_mode,
+            &addrs,
+            inherited.as_ref(),
+        )?;
+        print!("{summary}");
+        return Ok(());
+    }
+
+    // run the start command, before anything is served
+    if let Some(command) = &args.exec_on_start {
+        println!("Running {command}");
+        let status = shell_command(command).status()?;
+        if !status.success() {
+            anyhow::bail!("Start command `{command}` failed with {status}");
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut debouncer = FileWatcher::new(&args, sender)?;
+
+    // keep serving if watching fails, just without reloading
+    if let Err(e) = debouncer.watcher().watch(&watch_path, recursive_mode) {
+        println!("{}", color::red(watch_error_message(&watch_path, &e)));
+        print_error!("Not watching for changes, pages won't reload");
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => lua::temp_out()?,
+    };
+
+    let server = Arc::new(Server {
+        path,
+        site_script,
+        browser_url: browser_url(
+            &match &inherited {
+                Some(listener) => listener.local_addr()?.to_string(),
+                None => addrs[0].clone(),
+            },
+            args.base_url.as_deref().unwrap_or(""),
+            &args.open_path,
+        ),
+        update_notify_script: update_notify_script(&args)?,
+        args,
+        update_notify: Mutex::new(Vec::new()),
+        header_rules: RwLock::new(Vec::new()),
+        stats: Stats::default(),
+    });
+
+    // initial build of the site
+    if let Err(e) = server.build_site() {
+        print_error!("While building the site: {:?}", e);
+    }
+    server.reload_header_rules();
+    warn_shadowed(&server.path, &server.args);
+
+    // drop clients that left, so the count stays right between reloads
+    let server_cloned = server.clone();
+    thread::spawn(move || loop {
+        thread::sleep(REAP_INTERVAL);
+        server_cloned.reap_clients();
+    });
+
+    // reload everyone that connected before the first build was done
+    if let Some(delay) = server.args.initial_reload {
+        let server = server.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(delay));
+            let generation = server.stats.generation.fetch_add(1, Ordering::Relaxed) + 1;
+            let message = format!("id: {generation}\ndata: update\n\n");
+            let mut stream = server.update_notify.lock().expect(RW_ERR);
+            stream.retain_mut(|s| s.send(message.as_bytes()).is_ok());
+        });
+    }
+
+    // handle file changes in the background
+    let server_cloned = server.clone();
+    thread::spawn(move || {
+        watch_changes(
+            &server_cloned,
+            debouncer,
+            receiver,
+            &watch_path,
+            recursive_mode,
+        )
+    });
+
+    // print a summary when stopping
+    let server_cloned = server.clone();
+    ctrlc::set_handler(move || {
+        // end the event streams cleanly
+        for stream in server_cloned.update_notify.lock().expect(RW_ERR).drain(..) {
+            let _ = stream.finish();
         }
-        Err(e) => println!("[ERR] While watching files: {:?}", e),
+
+        println!("{}", server_cloned.stats.summary());
+        server_cloned.remove_temp_out();
+        std::process::exit(0);
     })?;
 
-    // watch the current dir
-    debouncer.watcher().watch(
-        if path.is_file() {
-            path.parent()
-                .expect("File does not have a parent directory")
-        } else {
-            &path
-        },
-        RecursiveMode::Recursive,
-    )?;
+    // the temporary output is only needed while serving
+    let result = listen(&server, &addrs, inherited);
+    server.remove_temp_out();
+    result
+}
 
-    // listen to incoming requests
-    let addr = addr.unwrap_or("127.0.0.1:1111".to_string());
-    let listener = TcpListener::bind(&addr)?;
-    println!("listening on {}", addr);
+/// Whether to open the browser again after a reload, when no browser was there to get it
+///
+/// Not more than once every few seconds, so a page that fails to load doesn't open tab after tab
+fn should_reopen(args: &Args, clients: usize, last_reopen: Option<Instant>) -> bool {
+    args.open_on_change
+        && clients == 0
+        && last_reopen.is_none_or(|t| t.elapsed() >= REOPEN_INTERVAL)
+}
 
-    for stream in listener.incoming() {
-        if let Err(e) = handle_connection(stream?, &path, &update_notify) {
-            println!("[ERR] While responding to request: {:?}", e);
+/// What serving would do, for --dry-run
+fn dry_run_summary(
+    args: &Args,
+    root: Option<&Path>,
+    watch_path: &Path,
+    recursive_mode: RecursiveMode,
+    addrs: &[String],
+    inherited: Option<&TcpListener>,
+) -> Result<String, anyhow::Error> {
+    let mut summary = match root {
+        Some(root) => format!(
+            "root: {}\n",
+            fs::canonicalize(root)
+                .unwrap_or(root.to_path_buf())
+                .display()
+        ),
+        None => "root: (temporary directory)\n".to_string(),
+    };
+    summary.push_str(&format!(
+        "watching: {} ({:?})\n",
+        watch_path.display(),
+        recursive_mode
+    ));
+    for addr in addrs {
+        summary.push_str(&format!("address: {addr}\n"));
+    }
+    if let Some(listener) = inherited {
+        summary.push_str(&format!("address: {} (fd)\n", listener.local_addr()?));
+    }
+    summary.push_str(&format!("{:#?}\n", args));
+    Ok(summary)
+}
+
+/// Rebuild and reload on every relevant change the watcher sees, until it stops
+fn watch_changes(
+    server: &Server,
+    mut debouncer: FileWatcher,
+    receiver: mpsc::Receiver<DebounceEventResult>,
+    watch_path: &Path,
+    recursive_mode: RecursiveMode,
+) {
+    let mut last_reopen: Option<Instant> = None;
+    let exclude_dirs = &server.args.exclude_dir;
+
+    let full_watch_path = fs::canonicalize(watch_path).unwrap_or(watch_path.to_path_buf());
+
+    // events come with absolute paths, canonical on some platforms and not on others
+    let watch_roots = [
+        full_watch_path.clone(),
+        std::path::absolute(watch_path).unwrap_or(watch_path.to_path_buf()),
+    ];
+
+    // built site, when it's in the watched directory it shouldn't cause a rebuild
+    let site_out = server
+        .site_script
+        .as_ref()
+        .and_then(|_| site_out_part(&server.path, &watch_roots));
+
+    // links to files outside of the watched directory don't get their changes seen, so watch those too
+    let mut watched_targets = HashSet::new();
+    if server.args.watch_symlink_targets {
+        let recursive = recursive_mode == RecursiveMode::Recursive;
+        for target in symlink_targets(watch_path, &full_watch_path, recursive, exclude_dirs) {
+            watch_target(debouncer.watcher(), &target, &mut watched_targets);
         }
     }
 
-    Ok(())
+    // changes that can cause a reload, not in excluded directories or the built site
+    let relevant = |e: &DebouncedEvent| {
+        !is_excluded(&e.path, &watch_roots, exclude_dirs)
+            && reload_strategy(&server.args, &e.path) != ReloadStrategy::Ignore
+            && !is_reload_excluded(&e.path, &watch_roots, &server.args)
+            && site_out.as_ref().is_none_or(|out| {
+                !watched_part(&e.path, &watch_roots).is_some_and(|path| path.starts_with(out))
+            })
+    };
+    let min_interval = Duration::from_millis(server.args.reload_min_interval);
+    let mut last_reload: Option<Instant> = None;
+
+    for res in receiver.iter() {
+        match res {
+            Ok(events) => {
+                let mut events = events.into_iter().filter(relevant).collect::<Vec<_>>();
+                if events.is_empty() {
+                    continue;
+                }
+
+                // reloaded not long ago, so wait and take everything that changed meanwhile along
+                let wait = last_reload.map_or(Duration::ZERO, |last| {
+                    min_interval.saturating_sub(last.elapsed())
+                });
+                if !wait.is_zero() {
+                    thread::sleep(wait);
+                    for res in receiver.try_iter() {
+                        match res {
+                            Ok(more) => events.extend(more.into_iter().filter(relevant)),
+                            Err(e) => print_error!("While watching files: {:?}", e),
+                        }
+                    }
+                }
+                last_reload = Some(Instant::now());
+
+                // directories made after starting are picked up by the recursive watcher, links aren't
+                if server.args.watch_symlink_targets {
+                    let targets = events
+                        .iter()
+                        .filter(|e| e.path.is_symlink())
+                        .filter_map(|e| fs::canonicalize(&e.path).ok())
+                        .filter(|target| !target.starts_with(&full_watch_path))
+                        .collect::<Vec<_>>();
+                    for target in targets {
+                        watch_target(debouncer.watcher(), &target, &mut watched_targets);
+                    }
+                }
+
+                // rebuild the site first
+                if server.site_script.is_some() {
+                    println!("{}", color::yellow("Files changed, rebuilding"));
+                    if let Err(e) = server.build_site() {
+                        print_error!("While building the site: {:?}", e);
+                        continue;
+                    }
+                }
+
+                // the _headers file might have changed with the rest
+                server.reload_header_rules();
+
+                // give slow builds time to finish writing
+                thread::sleep(Duration::from_millis(server.args.reload_delay));
+
+                println!("{}", color::yellow("Files changed, reloading"));
+
+                let message = reload_message(&server.args, &events);
+
+                // notify the upate, numbered so clients can tell they missed one
+                let generation = server.stats.generation.fetch_add(1, Ordering::Relaxed) + 1;
+                let message = format!("id: {generation}\ndata: {message}\n\n");
+                let mut stream = server.update_notify.lock().expect(RW_ERR);
+                stream.retain_mut(|s| s.send(message.as_bytes()).is_ok());
+
+                // line for scripts, with the time in milliseconds and the changed paths
+                if server.args.stdout_reload {
+                    let time = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis();
+                    let paths = events.iter().map(|e| e.path.as_path());
+                    println!("{}", reload_line(time, paths, &watch_roots));
+                }
+
+                // nobody got the update, so open the browser again
+                if should_reopen(&server.args, stream.len(), last_reopen) {
+                    last_reopen = Some(Instant::now());
+                    if let Err(e) = open_browser(&server.browser_url) {
+                        print_error!("While opening the browser: {:?}", e);
+                    }
+                }
+            }
+            Err(e) => print_error!("While watching files: {:?}", e),
+        }
+    }
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
-    path: &PathBuf,
-    update_notify: &Arc<Mutex<Vec<TcpStream>>>,
+/// Listen to incoming requests on all addresses, until a listener can't go on
+fn listen(
+    server: &Arc<Server>,
+    addrs: &[String],
+    inherited: Option<TcpListener>,
 ) -> Result<(), anyhow::Error> {
-    let reader = BufReader::new(&mut stream);
-    let request = reader.lines().next().unwrap_or(Ok("".to_string()))?;
+    let args = &server.args;
+    let mut listeners = addrs
+        .iter()
+        .map(|addr| {
+            let listener = bind_with_retry(addr, args)?;
+            println!("{}", color::green(format_args!("listening on {addr}")));
+            Ok(listener)
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    if let Some(listener) = inherited {
+        let addr = listener.local_addr()?;
+        println!("{}", color::green(format_args!("listening on {addr} (fd)")));
+        listeners.push(listener);
+    }
+
+    // open the browser, now that there's something to connect to
+    if args.open {
+        if let Err(e) = open_browser(&server.browser_url) {
+            print_error!("While opening the browser: {:?}", e);
+        }
+    }
+
+    // a fixed number of workers answer the connections, listeners wait for one to be free
+    let threads = args.threads.map_or(DEFAULT_THREADS, NonZeroUsize::get);
+    let sender = spawn_workers(server, threads);
+
+    // accept connections on every listener, they only stop when they can't go on
+    let (stopped, listener_stopped) = mpsc::channel();
+    for listener in listeners {
+        let server = server.clone();
+        let sender = sender.clone();
+        let stopped = stopped.clone();
+        thread::spawn(move || {
+            let _ = stopped.send(accept_connections(listener, &server, &sender));
+        });
+    }
+
+    drop(stopped);
 
-    // trim the request
-    let file_path = request
-        .trim_start_matches("GET")
-        .trim_end_matches("HTTP/1.1")
-        .trim()
-        .trim_start_matches("/");
+    // so stop as soon as any listener does
+    listener_stopped.recv().expect("Listener thread panicked")
+}
 
-    // try and get the file
-    let (content, status, mime_type) = if let Some(file) = fs::read(&path.join(file_path)).ok() {
-        // get the file content
-        (file, "200 OK", get_mime_type(&file_path))
+/// Start the workers that answer connections, they stop once the sender is dropped
+fn spawn_workers(server: &Arc<Server>, threads: usize) -> mpsc::SyncSender<TcpStream> {
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(0);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..threads {
+        let server = server.clone();
+        let receiver = receiver.clone();
+        thread::spawn(move || loop {
+            // only hold the lock while waiting, not while answering
+            let stream = receiver.lock().expect(RW_ERR).recv();
+            match stream {
+                Ok(stream) => serve_connection(stream, &server),
+                Err(_) => break,
+            }
+        });
     }
-    // try to see if this was an index.html file
-    else if let Some(file) = fs::read(&path.join(file_path).join("index.html")).ok() {
-        (file, "200 OK", Some("text/html"))
+
+    sender
+}
+
+/// Bind to the address, waiting for the port to be let go of if asked to with --bind-retry
+fn bind_with_retry(addr: &str, args: &Args) -> io::Result<TcpListener> {
+    let mut retries = 0;
+    loop {
+        match bind(addr, args) {
+            Err(e) if e.kind() == ErrorKind::AddrInUse && retries < args.bind_retry => {
+                retries += 1;
+                let warning = format!(
+                    "[WARN] {addr} is in use, retrying ({retries}/{})",
+                    args.bind_retry
+                );
+                println!("{}", color::yellow(warning));
+                thread::sleep(BIND_RETRY_DELAY);
+            }
+            listener => return listener,
+        }
     }
-    // if it's the update notifier, set the update stream
-    else if file_path == VERY_LONG_PATH {
-        // we don't want to wait
-        stream.set_nodelay(true)?;
+}
 
-        // send the response
-        stream.write_all(
-                b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
-            )?;
-        stream.write_all(b"data: initial\n\n")?;
-        stream.flush()?;
+/// Listen on the first address the given one resolves to that can be bound
+///
+/// Like TcpListener::bind, but with a custom backlog of connections waiting to be accepted
+fn bind(addr: &str, args: &Args) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
 
-        // set the event stream, as we have one now
-        update_notify.lock().expect(RW_ERR).push(stream);
+        // rebind right away after a restart, even with old connections in TIME_WAIT
+        // on windows this would allow taking over a port that's in use, so std doesn't do it either
+        if !cfg!(target_os = "windows") {
+            socket.set_reuse_address(true)?;
+        }
 
-        // don't need to send more
+        // let several servers share the port
+        #[cfg(unix)]
+        if args.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+
+        match socket
+            .bind(&addr.into())
+            .and_then(|_| socket.listen(args.backlog))
+        {
+            Ok(()) => return Ok(socket.into()),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "address resolved to nothing")))
+}
+
+/// Take over a listening socket that was passed in, like with systemd socket activation
+#[cfg(unix)]
+fn listen_fd(fd: i32) -> Result<TcpListener, anyhow::Error> {
+    use socket2::SockRef;
+    use std::os::fd::{BorrowedFd, FromRawFd};
+
+    // only borrow the fd while checking it, so a wrong one isn't closed, like stdout
+    // safety: the fd stays open for as long as it's borrowed, as nothing else closes it
+    let fd_ref = unsafe { BorrowedFd::borrow_raw(fd) };
+    let socket = SockRef::from(&fd_ref);
+    let kind = socket
+        .r#type()
+        .map_err(|e| anyhow::anyhow!("fd {fd} is not a socket: {e}"))?;
+    if kind != Type::STREAM || socket.local_addr()?.as_socket().is_none() {
+        anyhow::bail!("fd {fd} is not a tcp socket");
+    }
+
+    #[cfg(target_os = "linux")]
+    if !socket.is_listener()? {
+        anyhow::bail!("fd {fd} is not listening");
+    }
+
+    // it's accepted from with blocking calls, and shouldn't leak into the commands that are run
+    socket.set_nonblocking(false)?;
+    socket.set_cloexec(true)?;
+
+    // safety: the fd was handed to us to use as the listener, nothing else owns it
+    Ok(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// Sockets can't be passed in like this on other platforms
+#[cfg(not(unix))]
+fn listen_fd(_fd: i32) -> Result<TcpListener, anyhow::Error> {
+    anyhow::bail!("--listen-fd is only supported on unix")
+}
+
+/// Accept all incoming connections on a listener, and pass them on to the workers
+fn accept_connections(
+    listener: TcpListener,
+    server: &Server,
+    workers: &mpsc::SyncSender<TcpStream>,
+) -> Result<(), anyhow::Error> {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            // keep accepting after errors that go away on their own
+            Err(e) if is_transient_accept_error(&e) => {
+                print_error!("While accepting a connection: {:?}", e);
+                thread::sleep(ACCEPT_RETRY_DELAY);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        server.stats.connections.fetch_add(1, Ordering::Relaxed);
+        workers.send(stream)?;
+    }
+
+    Ok(())
+}
+
+/// Answer a connection, and log what went wrong
+///
+/// The reload stream is handed off to the update clients, so it doesn't keep the worker busy
+fn serve_connection(stream: TcpStream, server: &Server) {
+    if let Err(e) = handle_connection(stream, server) {
+        // the client going away isn't a problem with the server
+        if client_left(&e) {
+            println!("Client left before getting the full response: {e}");
+        } else {
+            print_error!("While responding to request: {:?}", e);
+        }
+    }
+}
+
+/// Whether answering failed because the client closed the connection
+fn client_left(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+        )
+    })
+}
+
+/// Whether accepting a connection failed in a way that doesn't break the listener
+///
+/// Covers interrupts, clients that left before being accepted, and running out of file descriptors or buffers
+fn is_transient_accept_error(e: &io::Error) -> bool {
+    // ENFILE, EMFILE and ENOBUFS, or their winsock versions, ENOBUFS differs between systems
+    #[cfg(target_os = "windows")]
+    const RESOURCE_ERRORS: &[i32] = &[10024, 10055];
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    const RESOURCE_ERRORS: &[i32] = &[23, 24, 55];
+    #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+    const RESOURCE_ERRORS: &[i32] = &[23, 24, 132];
+    #[cfg(not(any(
+        target_os = "windows",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        target_os = "solaris",
+        target_os = "illumos"
+    )))]
+    const RESOURCE_ERRORS: &[i32] = &[23, 24, 105];
+
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::OutOfMemory
+    ) || e
+        .raw_os_error()
+        .is_some_and(|code| RESOURCE_ERRORS.contains(&code))
+}
+
+fn handle_connection(mut stream: TcpStream, server: &Server) -> Result<(), anyhow::Error> {
+    let path = &server.path;
+    let args = &server.args;
+
+    // don't wait forever on clients that don't send anything
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // small responses shouldn't wait for more data to send
+    stream.set_nodelay(!args.no_nodelay)?;
+
+    // nothing was requested, or too much
+    let request = match Request::read(&mut stream, args.max_uri_length) {
+        Ok(Some(request)) => request,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            let Some(too_large) = TooLarge::from_io(&e) else {
+                return Err(e.into());
+            };
+            let status = if too_large.line_too_long {
+                "414 URI TOO LONG"
+            } else {
+                "431 REQUEST HEADER FIELDS TOO LARGE"
+            };
+            warn_refused(&stream.peer_addr()?.ip(), &too_large.to_string(), status);
+
+            let content = error_page(server, status, &too_large.to_string());
+            return send_response(
+                &mut stream,
+                server,
+                false,
+                status,
+                Some("text/html"),
+                &[],
+                &content,
+            );
+        }
+    };
+    server.stats.requests.fetch_add(1, Ordering::Relaxed);
+
+    // show what the client sent exactly
+    if args.trace {
+        let peer = stream.peer_addr()?;
+        for line in request.trace_lines() {
+            println!("[TRACE] {peer} > {line}");
+        }
+    }
+
+    // the client behind the proxy, if we're told to trust it
+    let peer = stream.peer_addr()?.ip();
+    let peer = match args.trust_proxy {
+        Some(header) => forwarded_client(&request, header).unwrap_or(peer),
+        None => peer,
+    };
+
+    if args.log_requests {
+        let target = match &request.query {
+            Some(query) => format!("{}?{query}", request.path),
+            None => request.path.clone(),
+        };
+        println!("{peer} {} {target}", request.method);
+    }
+
+    let method = request.method.as_str();
+    let head_only = method == "HEAD";
+
+    // refuse clients that aren't allowed
+    if !is_allowed(args, &peer) {
+        let status = "403 FORBIDDEN";
+        let content = error_page(server, status, &format!("{peer} is not allowed"));
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            status,
+            Some("text/html"),
+            &[],
+            &content,
+        );
+    }
+    let target = request.path.as_str();
+
+    // http/2 clients with prior knowledge start with a preface, tell them to use http/1.1 instead
+    if method == "PRI" && target == "*" && request.version == "HTTP/2.0" {
+        stream.write_all(HTTP2_REJECT)?;
         return Ok(());
     }
-    // otherwise use the default 404
-    else {
-        (
-            format!(
-                "<!DOCTYPE html><h1>404: Not found</h1><p>page {} not found</p>",
-                file_path
-            )
-            .into_bytes(),
+
+    // refuse targets that are too long, the head as a whole is limited separately
+    let target_length = request.path.len() + request.query.as_ref().map_or(0, |q| q.len() + 1);
+    if target_length > args.max_uri_length {
+        let status = "414 URI TOO LONG";
+        warn_refused(&peer, &format!("{method} {target}"), status);
+        let content = error_page(
+            server,
+            status,
+            &format!(
+                "the requested url is longer than {} bytes",
+                args.max_uri_length
+            ),
+        );
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            status,
+            Some("text/html"),
+            &[],
+            &content,
+        );
+    }
+
+    // cors preflight, for origins that are allowed
+    let cors = cors_headers(args, &request);
+    if method == "OPTIONS"
+        && !cors.is_empty()
+        && request.header("Access-Control-Request-Method").is_some()
+    {
+        let mut headers = cors
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect::<Vec<_>>();
+        let methods = args.cors_methods.join(", ");
+        headers.push(("Access-Control-Allow-Methods", &methods));
+
+        // the configured headers, or else whatever the client asked for
+        let allowed_headers = args.cors_headers.join(", ");
+        if !args.cors_headers.is_empty() {
+            headers.push(("Access-Control-Allow-Headers", &allowed_headers));
+        } else if let Some(request_headers) = request.header("Access-Control-Request-Headers") {
+            headers.push(("Access-Control-Allow-Headers", request_headers));
+        }
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            "204 NO CONTENT",
+            None,
+            &headers,
+            &[],
+        );
+    }
+
+    // asking which methods the server can be used with, pages are answered once they're found
+    if method == "OPTIONS" && target == "*" {
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            "204 NO CONTENT",
+            None,
+            &[("Allow", ALLOWED_METHODS)],
+            &[],
+        );
+    }
+
+    // only reading is supported
+    let refused = match method {
+        "GET" | "HEAD" | "OPTIONS" => None,
+        "POST" | "PUT" | "DELETE" | "CONNECT" | "TRACE" | "PATCH" => {
+            Some(("405 METHOD NOT ALLOWED", "allowed"))
+        }
+        _ => Some(("501 NOT IMPLEMENTED", "implemented")),
+    };
+    if let Some((status, reason)) = refused {
+        warn_refused(&peer, &format!("{method} {target}"), status);
+
+        let headers: &[(&str, &str)] = if status.starts_with("405") {
+            &[("Allow", ALLOWED_METHODS)]
+        } else {
+            &[]
+        };
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            status,
+            Some("text/html"),
+            headers,
+            &error_page(server, status, &format!("method {method} is not {reason}")),
+        );
+    }
+
+    // strip the base url, anything outside of it is not served
+    let Some(file_path) = strip_base_url(target, args.base_url.as_deref().unwrap_or("")) else {
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
             "404 NOT FOUND",
             Some("text/html"),
-        )
+            &[],
+            &not_found_page(server, target),
+        );
+    };
+    let Some(file_path) = normalize_path(file_path) else {
+        let status = "400 BAD REQUEST";
+        warn_refused(&peer, &format!("{method} {target}"), status);
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            status,
+            Some("text/html"),
+            &[],
+            &error_page(server, status, "the requested path is not valid"),
+        );
     };
+    let file_path = file_path.as_str();
 
-    // update notify script
-    let update_notify = if mime_type == Some("text/html") {
-        UPDATE_NOTIFY_SCRIPT
+    // send the root to the start page instead, keeping the query
+    if let Some(redirect) = args
+        .index_redirect
+        .as_ref()
+        .filter(|_| file_path.is_empty())
+    {
+        let mut location = site_path(args.base_url.as_deref().unwrap_or(""), redirect);
+        if let Some(query) = &request.query {
+            location.push_str(&format!("?{query}"));
+        }
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            "302 FOUND",
+            None,
+            &[("Location", &location)],
+            &[],
+        );
+    }
+
+    // OPTIONS only needs to know the page is there, so it isn't read
+    let read = |file: &Path| {
+        if method == "OPTIONS" {
+            check_served(server, file)
+        } else {
+            read_served(server, file)
+        }
+    };
+
+    // try and get the file, unless the server answers this path itself
+    let reserved = reserved_paths(args).any(|reserved| reserved == file_path);
+    let start = Instant::now();
+    let file = if reserved || is_hidden(path, &path.join(file_path)) {
+        Err(io::Error::from(ErrorKind::NotFound))
     } else {
-        ""
+        read(&path.join(file_path))
     };
+    let read_time = start.elapsed();
+    let index = args
+        .root_index
+        .as_ref()
+        .filter(|_| file_path.is_empty())
+        .map(|root_index| path.join(root_index))
+        .filter(|root_index| root_index.is_file())
+        .or_else(|| find_index(&path.join(file_path), &args.index))
+        .filter(|_| !reserved);
+    let index_file = index.as_ref().map(|index| read(index));
 
-    // send the page back
-    let length = content.len() + update_notify.len();
-    let response = format!(
-        "HTTP/1.1 {status}\r\nContent-Length: {length}\r\nCache-Control: no-cache\r\n{}\r\n",
-        if let Some(mime) = mime_type {
-            format!("Content-Type: {mime}\r\n")
+    // a refused index counts as the directory not being readable
+    let read_error = file
+        .as_ref()
+        .err()
+        .and_then(|e| read_error_status(e, &path.join(file_path)))
+        .or_else(|| {
+            let error = index_file.as_ref()?.as_ref().err()?;
+            read_error_status(error, index.as_ref()?)
+        });
+    let mut headers = cors
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect::<Vec<_>>();
+    let mut served = None;
+    let (content, status, mime_type) = if let Ok(file) = file {
+        let mime_type = get_mime_type(&file_path);
+        let file_path = path.join(file_path);
+        served = Some(file_path.clone());
+
+        // use a precompressed version if there is one, except for html as that needs the script added
+        let file = if args.precompressed
+            && mime_type != Some("text/html")
+            && precompressed::has_variants(&file_path)
+        {
+            headers.push(("Vary", "Accept-Encoding"));
+            let accept_encoding = request.header("Accept-Encoding").unwrap_or("");
+            match precompressed::find(&file_path, accept_encoding, &read) {
+                Some((compressed, encoding)) => {
+                    headers.push(("Content-Encoding", encoding));
+                    compressed
+                }
+                None => file,
+            }
         } else {
-            String::new()
-        }
-    );
+            file
+        };
 
-    // write response and page content
-    stream.write_all(response.as_bytes())?;
-    stream.write_all(&content)?;
-    stream.write_all(UPDATE_NOTIFY_SCRIPT.as_bytes())?;
+        (file, "200 OK", mime_type)
+    }
+    // try to see if this was a directory with an index file, /docs and /docs/ both serve it
+    else if let (Some(index), Some(Ok(file))) = (index, index_file) {
+        let mime_type = get_mime_type(&index);
+        served = Some(index);
+        (file, "200 OK", mime_type)
+    }
+    // try the html file for paths without an extension
+    else if let Some((file, page)) = (args.clean_urls && !reserved)
+        .then(|| find_clean_url(path, file_path))
+        .flatten()
+        .and_then(|page| Some((read(&page).ok()?, page)))
+    {
+        let mime_type = get_mime_type(&page);
+        served = Some(page);
+        (file, "200 OK", mime_type)
+    }
+    // if it's the update notifier, set the update stream
+    else if file_path == VERY_LONG_PATH && method == "GET" {
+        // we don't want to wait, even if other responses can
+        stream.set_nodelay(true)?;
 
-    Ok(())
-}
+        // send the response, the stream never ends so it's sent in chunks
+        let mut head = String::from(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nTransfer-Encoding: chunked\r\n",
+        );
 
-/// Get a mime type from a file path
-fn get_mime_type<P: AsRef<Path>>(path: &P) -> Option<&str> {
-    // see https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/MIME_types/Common_types
-    match path.as_ref().extension()?.to_str()? {
-        "aac" => Some("audio/aac"),
-        "abw" => Some("application/x-abiword"),
-        "apng" => Some("image/apng"),
-        "arc" => Some("application/x-freearc"),
-        "avif" => Some("image/avif"),
-        "avi" => Some("video/x-msvideo"),
-        "azw" => Some("application/vnd.amazon.ebook"),
-        "bin" => Some("application/octet-stream"),
-        "bmp" => Some("image/bmp"),
-        "bz" => Some("application/x-bzip"),
-        "bz2" => Some("application/x-bzip2"),
-        "cda" => Some("application/x-cdf"),
-        "csh" => Some("application/x-csh"),
-        "css" => Some("text/css"),
-        "csv" => Some("text/csv"),
-        "doc" => Some("application/msword"),
-        "docx" => Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
-        "eot" => Some("application/vnd.ms-fontobject"),
-        "epub" => Some("application/epub+zip"),
-        "gz" => Some("application/gzip"),
-        "gif" => Some("application/gif"),
-        "htm" | "html" => Some("text/html"),
-        "ico" => Some("image/vnd.microsoft.icon"),
-        "ics" => Some("text/calendar"),
-        "jar" => Some("application/java-archive"),
-        "jpeg" | "jpg" => Some("image/jpeg"),
-        "js" => Some("text/javascript"),
-        "json" => Some("application/json"),
-        "jsonld" => Some("application/ld+json"),
-        "mid" | "midi" => Some("audio/midi"),
-        "mjs" => Some("text/javascript"),
-        "mp3" => Some("audio/mpeg"),
-        "mp4" => Some("video/mpeg"),
-        "mpeg" => Some("video/mpeg"),
-        "mpkg" => Some("application/vnd.apple.installer+xml"),
-        "odp" => Some("application/vnd.oasis.opendocument.presentation"),
-        "ods" => Some("application/vnd.oasis.opendocument.spreadsheet"),
-        "odt" => Some("application/vnd.oasis.opendocument.text"),
-        "oga" => Some("audio/ogg"),
-        "ogv" => Some("video/ogg"),
-        "ogx" => Some("application/ogg"),
-        "opus" => Some("audio/opus"),
-        "otf" => Some("font/otf"),
-        "png" => Some("image/png"),
-        "pdf" => Some("application/pdf"),
-        "php" => Some("application/x-httpd-php"),
-        "ppt" => Some("application/vnd.ms-powerpoint"),
-        "pptx" => Some("application/vnd/openxmlformats-officedocument.presentationml.presentation"),
-        "rar" => Some("application/vnd.rar"),
-        "rtf" => Some("application/rtf"),
-        "sh" => Some("application/x-sh"),
-        "svg" => Some("image/svg+xml"),
-        "tar" => Some("application/x-tar"),
-        "tif" | "tiff" => Some("image/tiff"),
-        "ts" => Some("video/mp2t"),
-        "ttf" => Some("font/ttf"),
-        "txt" => Some("text/plain"),
-        "vsd" => Some("application/vnd.visio"),
-        "wav" => Some("audio/wav"),
-        "weba" => Some("audio/webm"),
-        "webm" => Some("video/webm"),
-        "webp" => Some("image/webp"),
-        "woff" => Some("font/woff"),
-        "woff2" => Some("font/woff2"),
-        "xhtml" => Some("application/xhtml+xml"),
-        "xls" => Some("application/vnd.ms-exel"),
-        "xlsx" => Some("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
-        "xml" => Some("application/xml"),
-        "xul" => Some("application/vnd.mozilla.xul+xml"),
-        "zip" => Some("application/zip"),
-        "3pg" => Some("video/3gpp"),
-        "3g2" => Some("video/3ggp2"),
-        "7z" => Some("application/x-7z-compressed"),
-        // Missing for some reason
-        "wasm" => Some("application/wasm"),
-        _ => None,
-    }
-}
-
-#[derive(Parser)]
-struct Args {
-    /// Optional path to either a directory containing `site.lua`, or a lua file that builds the site
-    path: Option<PathBuf>,
+        // tell proxies to pass events on right away, instead of buffering or compressing them
+        if args.sse_no_buffer {
+            head.push_str("X-Accel-Buffering: no\r\nContent-Encoding: identity\r\n");
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        let mut stream = UpdateClient::new(stream)?;
 
-    /// Address to serve on, defaults to 127.0.0.1:1111
-    #[clap(short, long)]
-    address: Option<String>,
-}
+        // say which reload we're at, holding the lock so a reload can't slip in before we're added
+        let mut clients = server.update_notify.lock().expect(RW_ERR);
+        let generation = server.stats.generation.load(Ordering::Relaxed);
+        stream.send(format!("id: {generation}\ndata: initial\n\n").as_bytes())?;
 
-fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
-    serve(args.path.unwrap_or(PathBuf::from(".")), args.address)?;
-    Ok(())
+        // set the event stream, as we have one now
+        clients.push(stream);
+
+        // don't need to send more
+        return Ok(());
+    }
+    // HEAD or OPTIONS for the update stream, nothing to stream
+    else if file_path == VERY_LONG_PATH {
+        (Vec::new(), "200 OK", Some("text/event-stream"))
+    }
+    // status of the server
+    else if file_path == STATUS_PATH {
+        let clients = server.update_notify.lock().expect(RW_ERR).len();
+        (
+            server.stats.to_json(clients).into_bytes(),
+            "200 OK",
+            Some("application/json"),
+        )
+    }
+    // browsers connected for reloads
+    else if file_path == CLIENTS_PATH {
+        let clients = server
+            .update_notify
+            .lock()
+            .expect(RW_ERR)
+            .iter()
+            .map(|client| match client.peer() {
+                Some(peer) => format!("\"{peer}\""),
+                None => "null".to_string(),
+            })
+            .collect::<Vec<_>>();
+        (
+            format!(
+                "{{\"count\":{},\"clients\":[{}]}}",
+                clients.len(),
+                clients.join(",")
+            )
+            .into_bytes(),
+            "200 OK",
+            Some("application/json"),
+        )
+    }
+    // every file that can be served, a page at a time
+    else if file_path == FILES_PATH {
+        let param = |name| {
+            request
+                .query_param(name)
+                .and_then(|value| value.parse().ok())
+        };
+        let offset = param("offset").unwrap_or(0);
+        let limit = param("limit")
+            .unwrap_or(args.files_limit)
+            .min(args.files_limit);
+
+        let keep = |file: &Path| {
+            !reserved_paths(args).any(|reserved| file == path.join(reserved))
+                && !is_hidden(path, file)
+                && !is_outside_root(server, file).unwrap_or(true)
+        };
+        let files = listing::all_files(path, offset.saturating_add(limit), &keep)
+            .into_iter()
+            .skip(offset)
+            .map(|file| {
+                let base_url = args.base_url.as_deref().unwrap_or("");
+                listing::json_string(&site_path(base_url, &format!("/{file}")))
+            })
+            .collect::<Vec<_>>();
+        (
+            format!("[{}]", files.join(",")).into_bytes(),
+            "200 OK",
+            Some("application/json"),
+        )
+    }
+    // always healthy, for load balancers and orchestration to probe
+    else if file_path == args.health_path {
+        (b"ok".to_vec(), "200 OK", Some("text/plain"))
+    }
+    // use the default favicon if there's none
+    else if args.default_favicon && file_path == "favicon.ico" {
+        (
+            DEFAULT_FAVICON.to_vec(),
+            "200 OK",
+            Some("image/vnd.microsoft.icon"),
+        )
+    }
+    // the directory links outside of the served one, so it isn't listed
+    else if args.list_dirs
+        && path.join(file_path).is_dir()
+        && is_outside_root(server, &path.join(file_path)).unwrap_or(true)
+    {
+        let status = "403 FORBIDDEN";
+        (
+            error_page(
+                server,
+                status,
+                &format!("directory {file_path} could not be read"),
+            ),
+            status,
+            Some("text/html"),
+        )
+    }
+    // list the directory, as json if the client asked for it
+    else if args.list_dirs && path.join(file_path).is_dir() {
+        let dir = path.join(file_path);
+        match listing::read_entries(&dir) {
+            Ok(mut entries) => {
+                entries.retain(|entry| {
+                    let entry = dir.join(&entry.name);
+                    !is_hidden(path, &entry) && !is_outside_root(server, &entry).unwrap_or(true)
+                });
+                let wants_json = request
+                    .header("Accept")
+                    .is_some_and(|accept| accept.contains("application/json"))
+                    || request.query_param("format") == Some("json");
+
+                if wants_json {
+                    (
+                        listing::json_listing(&entries),
+                        "200 OK",
+                        Some("application/json"),
+                    )
+                } else {
+                    let url = match args.base_url.as_deref().unwrap_or("").trim_matches('/') {
+                        "" => format!("/{file_path}"),
+                        base_url => format!("/{base_url}/{file_path}"),
+                    };
+                    (
+                        listing::html_listing(&url, &entries),
+                        "200 OK",
+                        Some("text/html"),
+                    )
+                }
+            }
+            // the directory is there, but couldn't be listed
+            Err(e) => {
+                print_error!("While listing {}: {:?}", dir.display(), e);
+                let status = if e.kind() == ErrorKind::PermissionDenied {
+                    "403 FORBIDDEN"
+                } else {
+                    "500 INTERNAL SERVER ERROR"
+                };
+                (
+                    error_page(
+                        server,
+                        status,
+                        &format!("directory {file_path} could not be read"),
+                    ),
+                    status,
+                    Some("text/html"),
+                )
+            }
+        }
+    }
+    // a directory, but without an index
+    else if path.join(file_path).is_dir() && read_error.is_none() {
+        println!("No index file in directory /{file_path}");
+        (
+            no_index_page(server, file_path),
+            "404 NOT FOUND",
+            Some("text/html"),
+        )
+    }
+    // the file is there, but couldn't be read
+    else if let Some(status) = read_error {
+        (
+            error_page(
+                server,
+                status,
+                &format!("page {file_path} could not be read"),
+            ),
+            status,
+            Some("text/html"),
+        )
+    }
+    // serve the catch all page for anything else
+    else if let Some((file, page)) = args
+        .catch_all
+        .as_ref()
+        .and_then(|page| Some((fs::read(page).ok()?, page)))
+    {
+        (file, "200 OK", get_mime_type(page))
+    }
+    // otherwise use the default 404
+    else {
+        (
+            not_found_page(server, file_path),
+            "404 NOT FOUND",
+            Some("text/html"),
+        )
+    };
+
+    // asking which methods can be used with a page that exists
+    if method == "OPTIONS" && status == "200 OK" {
+        headers.push(("Allow", ALLOWED_METHODS));
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            "204 NO CONTENT",
+            None,
+            &headers,
+            &[],
+        );
+    }
+
+    // headers from the _headers rules for the path, then from the sidecar next to the file, which replace them
+    let sidecar = served
+        .as_deref()
+        .map(|file| read_sidecar(server, file))
+        .unwrap_or_default();
+    let url = format!("/{file_path}");
+    let file_headers = headers::for_path(&server.header_rules.read().expect(RW_ERR), &url)
+        .filter(|(name, _)| {
+            !sidecar
+                .iter()
+                .any(|(header, _)| header.eq_ignore_ascii_case(name))
+        })
+        .chain(&sidecar)
+        .cloned()
+        .collect::<Vec<_>>();
+    headers.extend(
+        file_headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    );
+    let sidecar_type = file_headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Type"));
+
+    // refuse files without a known type
+    if args.strict_mime && status == "200 OK" && mime_type.is_none() && !sidecar_type {
+        let status = "415 UNSUPPORTED MEDIA TYPE";
+        let content = error_page(
+            server,
+            status,
+            &format!("page {file_path} has an unknown type"),
+        );
+        return send_response(
+            &mut stream,
+            server,
+            head_only,
+            status,
+            Some("text/html"),
+            &[],
+            &content,
+        );
+    }
+
+    // how long it took to read and build, in milliseconds
+    let timing;
+    if args.server_timing {
+        timing = format!(
+            "read;dur={:.3}, build;dur={:.3}",
+            read_time.as_secs_f64() * 1000.0,
+            start.elapsed().as_secs_f64() * 1000.0
+        );
+        headers.push(("Server-Timing", &timing));
+    }
+
+    send_response(
+        &mut stream,
+        server,
+        head_only,
+        status,
+        mime_type,
+        &headers,
+        &content,
+    )
+}
+
+/// Log a refused request, always shown so clients sending bad or unexpected requests are easy to find
+fn warn_refused(peer: &IpAddr, request: &str, status: &str) {
+    println!("{}", color::yellow(refused_line(peer, request, status)));
+}
+
+/// Log line for a refused request, with what was asked for like `POST /form`
+fn refused_line(peer: &IpAddr, request: &str, status: &str) -> String {
+    format!("[WARN] {peer} {request}: {status}")
+}
+
+/// How pages are reloaded when a file changes
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum ReloadStrategy {
+    /// Reload the page
+    Full,
+
+    /// Swap the stylesheets
+    Style,
+
+    /// Don't reload
+    Ignore,
+}
+
+/// Reload strategy for a changed file, from its extension
+fn reload_strategy(args: &Args, path: &Path) -> ReloadStrategy {
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let strategy = args
+        .reload_strategy
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(ext))
+        .map(|(_, strategy)| *strategy);
+
+    match strategy {
+        Some(strategy) => strategy,
+        None if args.reload_css_only && ext == "css" => ReloadStrategy::Style,
+        None => ReloadStrategy::Full,
+    }
+}
+
+/// Message to send the pages for the changed files
+fn reload_message(args: &Args, events: &[DebouncedEvent]) -> &'static str {
+    // the debouncer doesn't say what happened, but missing files were deleted or renamed
+    if events.iter().any(|e| !e.path.exists()) {
+        "delete"
+    }
+    // only stylesheets changed, so swap them without reloading
+    else if events
+        .iter()
+        .all(|e| reload_strategy(args, &e.path) == ReloadStrategy::Style)
+    {
+        "css"
+    } else {
+        "update"
+    }
+}
+
+/// Header a proxy puts the address of the client in
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum ProxyHeader {
+    /// X-Forwarded-For, added by most proxies
+    XForwardedFor,
+
+    /// Forwarded, from RFC 7239
+    Forwarded,
+}
+
+/// Address of the client, from the header the proxy added
+///
+/// The proxy adds the address it got the request from at the end, anything before it was sent
+/// by the client or proxies in front, so it can't be trusted.
+/// Only the header the proxy adds is looked at, as it passes the other one on as the client sent it
+fn forwarded_client(request: &Request, header: ProxyHeader) -> Option<IpAddr> {
+    // the last entry of the last header, in case the proxy added its own header line
+    let last_entry = |name: &str| {
+        let header = request
+            .headers
+            .iter()
+            .rev()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))?;
+        header.1.rsplit(',').next()
+    };
+    let client = match header {
+        ProxyHeader::XForwardedFor => last_entry("X-Forwarded-For")?,
+        ProxyHeader::Forwarded => last_entry("Forwarded")?.split(';').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            name.trim().eq_ignore_ascii_case("for").then_some(value)
+        })?,
+    };
+
+    // can be quoted, have a port, and have ipv6 in brackets
+    let client = client.trim().trim_matches('"');
+    if let Some(rest) = client.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    client
+        .parse()
+        .ok()
+        .or_else(|| client.rsplit_once(':')?.0.parse().ok())
+}
+
+/// Whether a client is allowed to connect
+fn is_allowed(args: &Args, peer: &IpAddr) -> bool {
+    if args.localhost_only && !peer.to_canonical().is_loopback() {
+        return false;
+    }
+
+    // denied ranges win over allowed ones
+    !args.deny.iter().any(|range| range.contains(peer))
+        && (args.allow.is_empty() || args.allow.iter().any(|range| range.contains(peer)))
+}
+
+/// Cors headers for the request, if it comes from an allowed origin
+///
+/// Specific origins are echoed back and allow credentials, as that can't be done with *
+fn cors_headers(args: &Args, request: &Request) -> Vec<(&'static str, String)> {
+    let Some(origin) = request.header("Origin") else {
+        return Vec::new();
+    };
+
+    if args.cors_origin.iter().any(|allowed| allowed == "*") {
+        vec![("Access-Control-Allow-Origin", "*".to_string())]
+    } else if args
+        .cors_origin
+        .iter()
+        .any(|allowed| allowed.trim_end_matches('/') == origin)
+    {
+        vec![
+            ("Access-Control-Allow-Origin", origin.to_string()),
+            ("Access-Control-Allow-Credentials", "true".to_string()),
+            ("Vary", "Origin".to_string()),
+        ]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Send a response with the given content, and the update notify script if it's html
+fn send_response(
+    stream: &mut TcpStream,
+    server: &Server,
+    head_only: bool,
+    status: &str,
+    mime_type: Option<&str>,
+    headers: &[(&str, &str)],
+    content: &[u8],
+) -> Result<(), anyhow::Error> {
+    let args = &server.args;
+
+    // misses without any page
+    let (mime_type, content) = if args.no_404_page && status.starts_with("404") {
+        (None, &[][..])
+    } else {
+        (mime_type, content)
+    };
+
+    // update notify script, only for html
+    let update_notify = if mime_type == Some("text/html") {
+        server.update_notify_script.as_str()
+    } else {
+        ""
+    };
+
+    // custom markup at the end of the head, only for html as well
+    let with_head;
+    let content = match &args.inject_head {
+        Some(inject) if mime_type == Some("text/html") => {
+            with_head = insert_head(content, &head_markup(inject));
+            with_head.as_deref().unwrap_or(content)
+        }
+        _ => content,
+    };
+
+    // send the page back
+    let length = content.len() + update_notify.len();
+
+    // caching rule for the type, or never cache
+    let cache_control = mime_type
+        .and_then(|mime| {
+            args.cache_control_for
+                .iter()
+                .find(|(pattern, _)| glob::matches(pattern, mime))
+        })
+        .map(|(_, value)| value.as_str())
+        .unwrap_or("no-cache");
+
+    let mut response = format!("HTTP/1.1 {status}\r\n");
+
+    // no content can't have a length
+    if !status.starts_with("204") {
+        response.push_str(&format!("Content-Length: {length}\r\n"));
+    }
+
+    // headers from --header or for this response replace the default ones
+    let is_default = |name: &str| {
+        !args.no_default_headers
+            && !args
+                .header
+                .iter()
+                .map(|(header, _)| header.as_str())
+                .chain(headers.iter().map(|(header, _)| *header))
+                .any(|header| header.eq_ignore_ascii_case(name))
+    };
+    if is_default("Cache-Control") {
+        response.push_str(&format!("Cache-Control: {cache_control}\r\n"));
+    }
+    if let Some(mime) = mime_type.filter(|_| is_default("Content-Type")) {
+        let content_type = content_type(mime, &args.charset);
+        response.push_str(&format!("Content-Type: {content_type}\r\n"));
+    }
+
+    // cross origin isolation, for SharedArrayBuffer
+    if args.coep {
+        response.push_str("Cross-Origin-Opener-Policy: same-origin\r\n");
+        response.push_str("Cross-Origin-Embedder-Policy: require-corp\r\n");
+    }
+
+    // don't let the browser guess other types
+    if args.nosniff {
+        response.push_str("X-Content-Type-Options: nosniff\r\n");
+    }
+
+    // extra headers, like from _headers, then the ones from --header they don't already set
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    let from_args = args.header.iter().filter(|(name, _)| {
+        !headers
+            .iter()
+            .any(|(header, _)| header.eq_ignore_ascii_case(name))
+    });
+    for (name, value) in from_args {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+
+    // write response and page content
+    // HEAD gets everything but the content, with the length it would have
+    stream.write_all(response.as_bytes())?;
+    let sent = if head_only {
+        0
+    } else {
+        stream.write_all(content)?;
+        stream.write_all(update_notify.as_bytes())?;
+        length
+    };
+
+    server
+        .stats
+        .bytes_sent
+        .fetch_add((response.len() + sent) as u64, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Content-Type for a mime type, text gets the charset attached
+fn content_type(mime: &str, charset: &str) -> String {
+    if is_text_mime_type(mime) {
+        format!("{mime}; charset={charset}")
+    } else {
+        mime.to_string()
+    }
+}
+
+/// Markup to add to the head, read from the file if it's a path to one
+fn head_markup(inject: &str) -> String {
+    if Path::new(inject).is_file() {
+        fs::read_to_string(inject).unwrap_or_else(|e| {
+            print_error!("While reading {}: {:?}", inject, e);
+            String::new()
+        })
+    } else {
+        inject.to_string()
+    }
+}
+
+/// Insert the markup right before `</head>`, if the page has one
+fn insert_head(page: &[u8], markup: &str) -> Option<Vec<u8>> {
+    let end = page
+        .windows(7)
+        .position(|tag| tag.eq_ignore_ascii_case(b"</head>"))?;
+
+    let mut content = Vec::with_capacity(page.len() + markup.len());
+    content.extend_from_slice(&page[..end]);
+    content.extend_from_slice(markup.as_bytes());
+    content.extend_from_slice(&page[end..]);
+    Some(content)
+}
+
+/// Fill in the update notify script from the arguments, it's read from --reload-script if given
+fn update_notify_script(args: &Args) -> Result<String, anyhow::Error> {
+    let script = match &args.reload_script {
+        Some(path) => {
+            let script = fs::read_to_string(path).map_err(|e| {
+                anyhow::anyhow!("While reading reload script {}: {e}", path.display())
+            })?;
+            if !script.contains("EventSource") {
+                let warning = format!(
+                    "[WARN] Reload script {} doesn't use an EventSource, so pages might not reload",
+                    path.display()
+                );
+                println!("{}", color::yellow(warning));
+            }
+            script
+        }
+        None => UPDATE_NOTIFY_SCRIPT.to_string(),
+    };
+
+    // point to the event stream and status under the base url
+    let base_url = args.base_url.as_deref().unwrap_or("");
+    let update_url = site_path(base_url, &format!("/{VERY_LONG_PATH}"));
+    let status_url = site_path(base_url, &format!("/{STATUS_PATH}"));
+
+    Ok(script
+        .replace("{update_url}", &update_url)
+        .replace("{status_url}", &status_url)
+        .replace("{reload_on_focus}", &args.reload_on_focus.to_string())
+        .replace("{reconnect_interval}", &args.reconnect_interval.to_string())
+        .replace("{client_log}", &args.client_log.to_string()))
+}
+
+/// Url to open in the browser
+fn browser_url(addr: &str, base_url: &str, open_path: &str) -> String {
+    format!("http://{addr}{}", site_path(base_url, open_path))
+}
+
+/// Absolute path of a page under the base url
+fn site_path(base_url: &str, path: &str) -> String {
+    match base_url.trim_matches('/') {
+        "" => path.to_string(),
+        base_url => format!("/{base_url}{path}"),
+    }
+}
+
+/// Command that runs the given command line in the shell
+fn shell_command(command: &str) -> Command {
+    if cfg!(target_os = "windows") {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+/// Open the url in the default browser
+fn open_browser(url: &str) -> Result<(), anyhow::Error> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", "", url]);
+        command
+    } else if cfg!(target_os = "macos") {
+        let mut command = Command::new("open");
+        command.arg(url);
+        command
+    } else {
+        let mut command = Command::new("xdg-open");
+        command.arg(url);
+        command
+    };
+
+    command.spawn()?;
+    Ok(())
+}
+
+/// Message for when watching a path failed, with tips for when the watch limit is hit
+fn watch_error_message(path: &Path, error: &notify::Error) -> String {
+    match error.kind {
+        notify::ErrorKind::MaxFilesWatch => format!(
+            "[ERR] While watching {}: ran out of file watches. \
+            On linux, raise the limit with `sudo sysctl fs.inotify.max_user_watches=524288`, \
+            only watch the top directory with --watch-root-only, or poll for changes with --poll",
+            path.display()
+        ),
+        _ => format!("[ERR] While watching {}: {:?}", path.display(), error),
+    }
+}
+
+/// Targets of the links in a directory that are outside of the root, skipping excluded directories
+///
+/// Linked directories aren't entered, so links can't loop
+fn symlink_targets(
+    dir: &Path,
+    root: &Path,
+    recursive: bool,
+    exclude_dirs: &[String],
+) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut targets = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let Ok(kind) = entry.file_type() else {
+            continue;
+        };
+
+        if kind.is_symlink() {
+            targets.extend(
+                fs::canonicalize(entry.path())
+                    .ok()
+                    .filter(|target| !target.starts_with(root)),
+            );
+        } else if kind.is_dir()
+            && recursive
+            && !exclude_dirs
+                .iter()
+                .any(|name| entry.file_name() == name.as_str())
+        {
+            targets.extend(symlink_targets(
+                &entry.path(),
+                root,
+                recursive,
+                exclude_dirs,
+            ));
+        }
+    }
+
+    targets
+}
+
+/// Watch the target of a link, if it isn't watched yet
+fn watch_target(watcher: &mut dyn Watcher, target: &Path, watched: &mut HashSet<PathBuf>) {
+    if !watched.insert(target.to_path_buf()) {
+        return;
+    }
+
+    let mode = if target.is_dir() {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    if let Err(e) = watcher.watch(target, mode) {
+        println!("{}", color::red(watch_error_message(target, &e)));
+    }
+}
+
+/// Whether a changed path is inside one of the excluded directories
+fn is_excluded(path: &Path, watch_roots: &[PathBuf], exclude_dirs: &[String]) -> bool {
+    // only look at the part inside the watched directory
+    let Some(path) = watched_part(path, watch_roots) else {
+        return false;
+    };
+
+    path.components()
+        .any(|c| exclude_dirs.iter().any(|dir| c.as_os_str() == dir.as_str()))
+}
+
+/// Part of a changed path inside the watched directory, none for changes to link targets outside it
+fn watched_part<'a>(path: &'a Path, watch_roots: &[PathBuf]) -> Option<&'a Path> {
+    watch_roots
+        .iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+}
+
+/// Part of the built site's path inside the watched directory, none if it's built elsewhere
+///
+/// The output doesn't have to exist yet, as a first build that fails doesn't make it
+fn site_out_part(out: &Path, watch_roots: &[PathBuf]) -> Option<PathBuf> {
+    [fs::canonicalize(out).ok(), std::path::absolute(out).ok()]
+        .into_iter()
+        .flatten()
+        .find_map(|out| watched_part(&out, watch_roots).map(Path::to_path_buf))
+}
+
+/// Line printed for --stdout-reload, with the changed paths relative to the watched directory
+///
+/// Paths are quoted and escaped like json strings, so ones with spaces or quotes can be read back
+fn reload_line<'a>(
+    time: u128,
+    paths: impl Iterator<Item = &'a Path>,
+    watch_roots: &[PathBuf],
+) -> String {
+    let paths = paths
+        .map(|path| {
+            let path = watched_part(path, watch_roots).unwrap_or(path);
+            listing::json_string(&path.to_string_lossy())
+        })
+        .collect::<Vec<_>>();
+    format!("RELOAD {time} {}", paths.join(" "))
+}
+
+/// Whether changes to the path shouldn't cause a reload, from --reload-exclude
+fn is_reload_excluded(path: &Path, watch_roots: &[PathBuf], args: &Args) -> bool {
+    let Some(path) = watched_part(path, watch_roots) else {
+        return false;
+    };
+    let path = path.to_string_lossy().replace('\\', "/");
+    args.reload_exclude
+        .iter()
+        .any(|pattern| glob::matches(pattern, &path))
+}
+
+/// 404 page
+fn not_found_page(server: &Server, file_path: &str) -> Vec<u8> {
+    error_page(
+        server,
+        "404 NOT FOUND",
+        &format!("page {} not found", file_path),
+    )
+}
+
+/// Paths the server answers itself, including the configured health check
+fn reserved_paths(args: &Args) -> impl Iterator<Item = &str> {
+    RESERVED_PATHS
+        .into_iter()
+        .chain([args.health_path.as_str()])
+}
+
+/// Warn about files that can't be served because the server uses their path itself
+fn warn_shadowed(path: &Path, args: &Args) {
+    for reserved in reserved_paths(args) {
+        let file = path.join(reserved);
+        if file.exists() {
+            let warning = format!(
+                "[WARN] {} is not served, the server uses /{reserved} itself",
+                file.display()
+            );
+            println!("{}", color::yellow(warning));
+        }
+    }
+}
+
+/// Headers from the `<file>.headers` sidecar next to a file, one `Name: Value` per line
+///
+/// Lines that aren't a header are skipped, so `#` can be used for comments
+fn read_sidecar(server: &Server, file: &Path) -> Vec<(String, String)> {
+    let mut sidecar = file.as_os_str().to_owned();
+    sidecar.push(".headers");
+
+    let sidecar = read_served(server, Path::new(&sidecar)).unwrap_or_default();
+    String::from_utf8_lossy(&sidecar)
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| parse_header(line).ok())
+        .collect()
+}
+
+/// Whether the file only configures how others are served, so it isn't served or listed itself
+///
+/// These are the `_headers` file in the root, and the `<file>.headers` sidecars next to the files they're for
+///
+/// Names are compared ignoring case, as filesystems on macos and windows do
+fn is_hidden(root: &Path, path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    if path.parent() == Some(root) && name.eq_ignore_ascii_case(HEADERS_FILE) {
+        return true;
+    }
+
+    // ascii lowercasing keeps the length, so the file name can be cut from the original
+    name.to_ascii_lowercase()
+        .strip_suffix(".headers")
+        .is_some_and(|file| path.with_file_name(&name[..file.len()]).is_file())
+}
+
+/// Read a file to serve, refusing symlinks out of the served directory if asked to
+fn read_served(server: &Server, path: &Path) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    open_served(server, path)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Check a file can be served without reading it, giving back no content
+fn check_served(server: &Server, path: &Path) -> io::Result<Vec<u8>> {
+    // directories can be opened, but not read
+    if open_served(server, path)?.metadata()?.is_dir() {
+        return Err(ErrorKind::IsADirectory.into());
+    }
+
+    Ok(Vec::new())
+}
+
+/// Open a file to serve, refusing symlinks out of the served directory if asked to
+fn open_served(server: &Server, path: &Path) -> io::Result<fs::File> {
+    if is_outside_root(server, path)? {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            "links outside of the served directory",
+        ));
+    }
+
+    fs::File::open(path)
+}
+
+/// Whether the path links outside of the served directory, when --confine-symlinks is set
+fn is_outside_root(server: &Server, path: &Path) -> io::Result<bool> {
+    if !server.args.confine_symlinks {
+        return Ok(false);
+    }
+
+    let root = fs::canonicalize(&server.path)?;
+    Ok(!fs::canonicalize(path)?.starts_with(root))
+}
+
+/// Find the index file of a directory
+///
+/// A `.index` file in the directory can list the index file names to use there, one per line,
+/// otherwise the names from --index are used. Only plain file names in the directory are used from it
+fn find_index(dir: &Path, index_names: &[String]) -> Option<PathBuf> {
+    let index_file = dir.join(".index");
+    let overrides = fs::read_to_string(&index_file).unwrap_or_default();
+    let overrides = overrides
+        .lines()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter(|name| {
+            // anything else could point outside of the served directory
+            let plain = is_file_name(name);
+            if !plain {
+                let warning = format!(
+                    "[WARN] Ignoring {name} in {}, only file names in the directory can be used",
+                    index_file.display()
+                );
+                println!("{}", color::yellow(warning));
+            }
+            plain
+        })
+        .collect::<Vec<_>>();
+
+    let names = if overrides.is_empty() {
+        index_names.iter().map(String::as_str).collect()
+    } else {
+        overrides
+    };
+
+    names
+        .into_iter()
+        .map(|name| dir.join(name))
+        .find(|index| index.is_file())
+}
+
+/// Whether the name is a file name on its own, without separators, `..` or anything absolute
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains(['/', '\\'])
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        )
+}
+
+/// Find the html page for a path without an extension, like about.html for /about
+fn find_clean_url(root: &Path, file_path: &str) -> Option<PathBuf> {
+    let path = root.join(file_path);
+    if file_path.is_empty() || path.extension().is_some() || path.exists() {
+        return None;
+    }
+
+    let page = root.join(format!("{file_path}.html"));
+    page.is_file().then_some(page)
+}
+
+/// 404 page for a directory without an index file, from the template if there is one
+fn no_index_page(server: &Server, dir: &str) -> Vec<u8> {
+    if let Some(template) = &server.args.no_index_page {
+        match fs::read_to_string(template) {
+            Ok(page) => {
+                let dir = listing::html_escape(&format!("/{dir}"));
+                return page.replace("{dir}", &dir).into_bytes();
+            }
+            Err(e) => print_error!("While reading {}: {:?}", template.display(), e),
+        }
+    }
+
+    // nothing to serve at all yet, so explain how to start
+    let is_empty = fs::read_dir(&server.path).is_ok_and(|mut entries| entries.next().is_none());
+    if dir.is_empty() && is_empty {
+        return error_page(
+            server,
+            "404 NOT FOUND",
+            &format!(
+                "The served directory {} is empty. Add an {} file to it, and this page will reload to show it",
+                server.path.display(),
+                server.args.index.first().map(String::as_str).unwrap_or("index.html"),
+            ),
+        );
+    }
+
+    error_page(
+        server,
+        "404 NOT FOUND",
+        &format!("No index file in directory /{dir}"),
+    )
+}
+
+/// Page for errors, from the error page directory if it has one for the status
+fn error_page(server: &Server, status: &str, message: &str) -> Vec<u8> {
+    // custom page
+    if let Some(dir) = &server.args.error_page_dir {
+        let code = status.split(' ').next().unwrap_or(status);
+        if let Ok(page) = fs::read(dir.join(format!("{code}.html"))) {
+            return page;
+        }
+    }
+
+    default_error_page(status, message)
+}
+
+/// Built in page for errors, turning "404 NOT FOUND" into "404: Not found"
+fn default_error_page(status: &str, message: &str) -> Vec<u8> {
+    let (code, reason) = status.split_once(' ').unwrap_or((status, ""));
+    let reason = reason.to_lowercase();
+    let mut chars = reason.chars();
+    let title = match chars.next() {
+        Some(first) => format!("{code}: {}{}", first.to_uppercase(), chars.as_str()),
+        None => code.to_string(),
+    };
+
+    // the message can hold the requested path, which is up to the client
+    let message = listing::html_escape(message);
+    format!("<!DOCTYPE html><h1>{title}</h1><p>{message}</p>").into_bytes()
+}
+
+/// Status to respond with when a file exists but reading it failed
+fn read_error_status(error: &io::Error, path: &Path) -> Option<&'static str> {
+    // a directory might have an index, windows refuses reading one instead of naming it
+    if path.is_dir() {
+        return None;
+    }
+
+    match error.kind() {
+        // not there
+        ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::IsADirectory => None,
+        ErrorKind::PermissionDenied => Some("403 FORBIDDEN"),
+        _ => Some("500 INTERNAL SERVER ERROR"),
+    }
+}
+
+/// Turn a request path into a relative path inside the served directory
+///
+/// This decodes percent escapes, drops empty and `.` segments, and resolves `..` without going
+/// above the root, so `//foo/./bar/../baz` becomes `foo/baz`.
+/// Backslashes separate segments as well, as they do on windows, and segments with a NUL are refused.
+/// On windows segments with a `:` are refused too, as those could name a drive or stream outside of the root
+fn normalize_path(path: &str) -> Option<String> {
+    // only windows gives a `:` a meaning, elsewhere it's a normal character in names
+    let refused: &[char] = if cfg!(windows) { &[':', '\0'] } else { &['\0'] };
+
+    let path = percent_decode(path);
+    let mut segments = Vec::new();
+
+    for segment in path.split(['/', '\\']) {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            segment if segment.contains(refused) => return None,
+            segment => segments.push(segment),
+        }
+    }
+
+    Some(segments.join("/"))
+}
+
+/// Decode percent escapes like `%20`, leaving invalid ones as they are
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+
+        if let Some(byte) = escaped {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Strip the base url from a request target, if the target is under it
+fn strip_base_url<'a>(target: &'a str, base_url: &str) -> Option<&'a str> {
+    let base_url = base_url.trim_matches('/');
+    if base_url.is_empty() {
+        return Some(target);
+    }
+
+    // clients can send extra slashes, normalize_path drops them in the rest
+    let rest = target.trim_start_matches('/').strip_prefix(base_url)?;
+
+    // only strip whole path segments
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Optional path to either a directory to serve, a directory containing `site.lua`, or a lua file that builds the site
+    path: Option<PathBuf>,
+
+    /// Directory to write the site built by the lua script to, defaults to a temporary directory
+    #[clap(long)]
+    out: Option<PathBuf>,
+
+    /// Remove everything in --out before building the site, refused when --out is or contains the directory of the script
+    #[clap(long)]
+    clean_out: bool,
+
+    /// Address to serve on, defaults to 127.0.0.1:1111, can be given multiple times
+    #[clap(short, long)]
+    address: Vec<String>,
+
+    /// Serve on an already listening socket with this file descriptor, instead of the default address. Only on unix
+    #[clap(long, value_parser = clap::value_parser!(i32).range(0..))]
+    listen_fd: Option<i32>,
+
+    /// How many connections can wait to be accepted, defaults to 128. The OS can cap this, like net.core.somaxconn on linux
+    #[clap(long, default_value_t = 128)]
+    backlog: i32,
+
+    /// Times to try again, half a second apart, when the address is in use. Unlike picking another port, this waits for it
+    #[clap(long, default_value_t = 0)]
+    bind_retry: u32,
+
+    /// Number of connections answered at the same time, defaults to 64. Reload streams don't count towards it, but idle connections browsers open ahead of time do
+    #[clap(long)]
+    threads: Option<NonZeroUsize>,
+
+    /// Allow other servers to listen on the same port (SO_REUSEPORT), only on unix
+    #[clap(long)]
+    reuse_port: bool,
+
+    /// Only serve clients in this address range, like 192.168.0.0/16, can be given multiple times
+    #[clap(long)]
+    allow: Vec<Cidr>,
+
+    /// Refuse clients in this address range, even if they're allowed, can be given multiple times
+    #[clap(long)]
+    deny: Vec<Cidr>,
+
+    /// Refuse clients that aren't on this machine, even when listening on other addresses
+    #[clap(long)]
+    localhost_only: bool,
+
+    /// Take the client address from the last entry of the header the proxy adds, for logs and --allow and --deny. The header is X-Forwarded-For, or Forwarded with --trust-proxy=forwarded. Only use this behind a proxy that adds it, as clients can send it too
+    #[clap(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "x-forwarded-for"
+    )]
+    trust_proxy: Option<ProxyHeader>,
+
+    /// Log the client address, method and url of every request
+    #[clap(long)]
+    log_requests: bool,
+
+    /// Most files /__files lists at once, use ?offset= to get the next ones
+    #[clap(long, default_value_t = 1000)]
+    files_limit: usize,
+
+    /// Charset to declare on text files, defaults to utf-8
+    #[clap(long, default_value = "utf-8")]
+    charset: String,
+
+    /// Path prefix to serve under, for when the server is behind a reverse proxy at a subpath
+    #[clap(long)]
+    base_url: Option<String>,
+
+    /// Also watch the targets of links that point outside of the watched directory, like linked packages in a workspace
+    #[clap(long)]
+    watch_symlink_targets: bool,
+
+    /// Directory name to ignore changes in, like `node_modules` or `target`, can be given multiple times
+    #[clap(long)]
+    exclude_dir: Vec<String>,
+
+    /// Command to run once before starting the server, like an initial build. The server doesn't start if it fails
+    #[clap(long)]
+    exec_on_start: Option<String>,
+
+    /// Open the served site in the browser
+    #[clap(long)]
+    open: bool,
+
+    /// When files change and no browser tab is connected, open a new one
+    #[clap(long)]
+    open_on_change: bool,
+
+    /// Path to open in the browser with --open, defaults to /
+    #[clap(long, default_value = "/", value_parser = parse_open_path)]
+    open_path: String,
+
+    /// Refuse to serve files with an unknown mime type, instead of sending them without a type
+    #[clap(long)]
+    strict_mime: bool,
+
+    /// Send the cross origin isolation headers, needed to use SharedArrayBuffer
+    #[clap(long)]
+    coep: bool,
+
+    /// Serve precompressed .br and .gz versions of files when they exist and the client accepts them
+    #[clap(long)]
+    precompressed: bool,
+
+    /// Origin that is allowed to make cross origin requests, can be given multiple times. * allows any origin, but without credentials
+    #[clap(long)]
+    cors_origin: Vec<String>,
+
+    /// Methods to allow in answers to cors preflights, comma separated or given multiple times
+    #[clap(long, default_value = "GET", value_delimiter = ',')]
+    cors_methods: Vec<String>,
+
+    /// Headers to allow in answers to cors preflights, comma separated or given multiple times. Without it, the headers the client asks for are allowed
+    #[clap(long, value_delimiter = ',')]
+    cors_headers: Vec<String>,
+
+    /// Let the OS batch small writes on connections (Nagle), except for the reload stream
+    #[clap(long)]
+    no_nodelay: bool,
+
+    /// Cache-Control for responses of a mime type, like image/*=max-age=86400. Can be given multiple times, the first match is used, others get no-cache
+    #[clap(long, value_parser = parse_cache_rule)]
+    cache_control_for: Vec<(String, String)>,
+
+    /// Header to add to every response, like "X-Frame-Options: DENY". Can be given multiple times, and replaces a default header with the same name. Headers from _headers and sidecars replace it in turn
+    #[clap(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+
+    /// Don't send Cache-Control and Content-Type, only Content-Length and headers from options like --header
+    #[clap(long)]
+    no_default_headers: bool,
+
+    /// Longest request url in bytes, longer ones get a 414. The headers get 64 KiB on top of this
+    #[clap(long, default_value_t = 8 * 1024)]
+    max_uri_length: usize,
+
+    /// Send a Server-Timing header with how long reading the file and building the response took
+    #[clap(long)]
+    server_timing: bool,
+
+    /// Redirect / to this path instead of serving the root index
+    #[clap(long, value_parser = parse_open_path)]
+    index_redirect: Option<String>,
+
+    /// Send X-Content-Type-Options: nosniff, so browsers stick to the sent Content-Type
+    #[clap(long)]
+    nosniff: bool,
+
+    /// Path that always answers 200 OK, for health checks. Like the other paths the server answers itself, it wins over files
+    #[clap(long, default_value = "__health", value_parser = parse_reserved_path)]
+    health_path: String,
+
+    /// Serve a blank favicon.ico if the site doesn't have one
+    #[clap(long)]
+    default_favicon: bool,
+
+    /// Milliseconds to wait for file changes to settle before handling them, defaults to 500
+    #[clap(long, default_value_t = 500)]
+    debounce: u64,
+
+    /// Poll for file changes instead of using the OS notifications, for network and container filesystems
+    #[clap(long)]
+    poll: bool,
+
+    /// Milliseconds between polls, defaults to 1000
+    #[clap(long, default_value_t = 1000)]
+    poll_interval: u64,
+
+    /// Compare file contents when polling instead of the modified time, slower but catches every change
+    #[clap(long, requires = "poll")]
+    poll_compare_contents: bool,
+
+    /// Reload all connected pages once, this many milliseconds after starting
+    #[clap(long)]
+    initial_reload: Option<u64>,
+
+    /// Least milliseconds between two reloads, changes in between are sent as one reload after it, defaults to 0
+    #[clap(long, default_value_t = 0)]
+    reload_min_interval: u64,
+
+    /// Extra milliseconds to wait after the changes settled before reloading, defaults to 0
+    #[clap(long, default_value_t = 0)]
+    reload_delay: u64,
+
+    /// Milliseconds the browser waits before reconnecting to the server, defaults to 3000
+    #[clap(long, default_value_t = 3000)]
+    reconnect_interval: u64,
+
+    /// Print `RELOAD <unix time in ms> <changed paths>` on each reload, for scripts to react to. The paths are quoted like json strings, separated by spaces
+    #[clap(long)]
+    stdout_reload: bool,
+
+    /// Log the raw head of every request, with Authorization headers left out
+    #[clap(long)]
+    trace: bool,
+
+    /// Don't reload when files matching this glob change, but still serve them. Matched against the path in the watched directory, * also matches across directories. Can be given multiple times
+    #[clap(long)]
+    reload_exclude: Vec<String>,
+
+    /// How to reload when files with an extension change, like md=ignore. Strategies are full, style and ignore, can be given multiple times
+    #[clap(long, value_parser = parse_reload_strategy)]
+    reload_strategy: Vec<(String, ReloadStrategy)>,
+
+    /// Markup to add to the head of html pages, or a file to read it from
+    #[clap(long)]
+    inject_head: Option<String>,
+
+    /// Swap the stylesheets instead of reloading the page when only css files changed, same as --reload-strategy css=style
+    #[clap(long)]
+    reload_css_only: bool,
+
+    /// Html to add to pages instead of the built in reload script. {update_url}, {reconnect_interval} and {client_log} in it are filled in, as are {status_url} and {reload_on_focus}
+    #[clap(long)]
+    reload_script: Option<PathBuf>,
+
+    /// Ask proxies not to buffer or compress the reload stream, for reloads that never arrive behind one
+    #[clap(long)]
+    sse_no_buffer: bool,
+
+    /// Reload when the tab comes back into view, if a reload was sent while it was in the background
+    #[clap(long)]
+    reload_on_focus: bool,
+
+    /// Log reload events to the browser console
+    #[clap(long)]
+    client_log: bool,
+
+    /// Directory with custom error pages, named after their status like `404.html` or `500.html`
+    #[clap(long)]
+    error_page_dir: Option<PathBuf>,
+
+    /// Name of the index file to serve for directories, can be given multiple times to try several.
+    /// Directories are served with or without a trailing slash, without a redirect
+    #[clap(long, default_value = "index.html")]
+    index: Vec<String>,
+
+    /// File to serve for the root of the site only, relative to the site. Other directories still use --index
+    #[clap(long)]
+    root_index: Option<PathBuf>,
+
+    /// Serve about.html for /about, if there is no file or directory named about
+    #[clap(long)]
+    clean_urls: bool,
+
+    /// List the files in directories without an index file, as json if the request accepts it
+    #[clap(long)]
+    list_dirs: bool,
+
+    /// Send 404 responses with an empty body, instead of an html page
+    #[clap(long)]
+    no_404_page: bool,
+
+    /// Refuse files, including index files, that are symlinks to outside of the served directory. Links that stay inside are still served
+    #[clap(long)]
+    confine_symlinks: bool,
+
+    /// Page to serve with a 200 for every request that doesn't match a file or directory. Directories without an index still get the no index page
+    #[clap(long)]
+    catch_all: Option<PathBuf>,
+
+    /// Page to show for directories without an index file, `{dir}` in it is replaced with the directory
+    #[clap(long)]
+    no_index_page: Option<PathBuf>,
+
+    /// Only watch the top directory for changes, not the directories in it.
+    /// This avoids running out of file watches on large trees, at the cost of not reloading on nested changes
+    #[clap(long)]
+    watch_root_only: bool,
+
+    /// Mime type to use for an extension, like md=text/markdown. Can be given multiple times or separated by commas
+    #[clap(long, value_parser = parse_mime, value_delimiter = ',')]
+    mime: Vec<(String, String)>,
+
+    /// Apache style mime.types file to read more mime types from, --mime still wins over it
+    #[clap(long)]
+    mime_file: Option<PathBuf>,
+
+    /// When to color the output
+    #[clap(long, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Print the known file extensions and their mime types, and exit
+    #[clap(long)]
+    list_mime: bool,
+
+    /// Print the resolved configuration and exit, without listening or watching
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// Check that a path given for the site is absolute
+fn parse_open_path(path: &str) -> Result<String, String> {
+    if path.starts_with('/') {
+        Ok(path.to_string())
+    } else {
+        Err("path must start with /".to_string())
+    }
+}
+
+/// Check that a path the server answers itself isn't the root, and take off the slashes around it
+fn parse_reserved_path(path: &str) -> Result<String, String> {
+    match path.trim_matches('/') {
+        "" => Err("path can't be the root of the site".to_string()),
+        path => Ok(path.to_string()),
+    }
+}
+
+/// Split a reload strategy into the extension and the strategy
+fn parse_reload_strategy(rule: &str) -> Result<(String, ReloadStrategy), String> {
+    let (ext, strategy) = rule
+        .split_once('=')
+        .ok_or("strategy must look like <extension>=<full|style|ignore>")?;
+    let strategy = ReloadStrategy::from_str(strategy.trim(), true)?;
+    Ok((ext.trim().trim_start_matches('.').to_string(), strategy))
+}
+
+/// Split a mime override into the extension and the mime type
+fn parse_mime(rule: &str) -> Result<(String, String), String> {
+    match rule.split_once('=') {
+        Some((ext, mime)) if !ext.trim().is_empty() && mime.contains('/') => Ok((
+            ext.trim().trim_start_matches('.').to_string(),
+            mime.trim().to_string(),
+        )),
+        _ => Err("mime type must look like <extension>=<type>/<subtype>".to_string()),
+    }
+}
+
+/// Split a header into the name and value
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    match header.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err("header must look like <name>: <value>".to_string()),
+    }
+}
+
+/// Split a cache rule into the mime pattern and the Cache-Control value
+fn parse_cache_rule(rule: &str) -> Result<(String, String), String> {
+    match rule.split_once('=') {
+        Some((pattern, value)) if !pattern.is_empty() && !value.is_empty() => {
+            Ok((pattern.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err("rule must look like <mime-glob>=<value>".to_string()),
+    }
+}
+
+/// Mime types from --mime and --mime-file, explicit types come first so they win over the ones from the file
+fn mime_overrides(args: &Args) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let mut overrides = args.mime.clone();
+    if let Some(mime_file) = &args.mime_file {
+        let types = mime::read_mime_file(mime_file)
+            .map_err(|e| anyhow::anyhow!("While reading mime file {}: {e}", mime_file.display()))?;
+        overrides.extend(types);
+    }
+
+    Ok(overrides)
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    color::init(args.color);
+
+    mime::set_overrides(mime_overrides(&args)?);
+
+    if args.list_mime {
+        mime::print_mime_types();
+        return Ok(());
+    }
+
+    serve(args)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{self, Read, Write},
+        net::{IpAddr, TcpListener, TcpStream},
+        path::{Path, PathBuf},
+        sync::{atomic::Ordering, mpsc, Arc, Mutex, RwLock},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use clap::Parser;
+    use notify_debouncer_mini::{
+        notify::{self, RecursiveMode},
+        DebounceEventResult, DebouncedEvent, DebouncedEventKind,
+    };
+
+    use super::{
+        accept_connections, bind, bind_with_retry, browser_url, client_left, content_type,
+        cors_headers, default_error_page, dry_run_summary, error_page, find_index,
+        forwarded_client, handle_connection, insert_head, is_allowed, is_excluded, is_hidden,
+        is_reload_excluded, is_transient_accept_error, listen, listen_fd, mime_overrides,
+        no_index_page, normalize_path, parse_reload_strategy, percent_decode, read_error_status,
+        refused_line, reload_line, reload_message, reload_strategy, serve, should_reopen,
+        site_out_part, site_path, spawn_workers, strip_base_url, update_notify_script,
+        watch_changes, watch_error_message, watched_part, Args, FileWatcher, ProxyHeader,
+        ReloadStrategy, Server, Stats, DEFAULT_FAVICON, HTTP2_REJECT, VERY_LONG_PATH,
+    };
+    use crate::{
+        mime::find_mime_type,
+        request::{Request, TooLarge},
+        update_client::UpdateClient,
+    };
+
+    #[test]
+    fn collapses_slashes_and_dot_segments() {
+        assert_eq!(normalize_path("/").unwrap(), "");
+        assert_eq!(normalize_path("//foo///bar//").unwrap(), "foo/bar");
+        assert_eq!(normalize_path("/foo/./bar/.").unwrap(), "foo/bar");
+        assert_eq!(normalize_path("/foo/bar/../baz").unwrap(), "foo/baz");
+        assert_eq!(normalize_path("//foo/./bar/../baz").unwrap(), "foo/baz");
+    }
+
+    #[test]
+    fn dot_dot_stays_inside_the_root() {
+        assert_eq!(normalize_path("/../../etc/passwd").unwrap(), "etc/passwd");
+        assert_eq!(normalize_path("/foo/../../bar").unwrap(), "bar");
+        assert_eq!(
+            normalize_path("/%2e%2e/%2E%2E/etc/passwd").unwrap(),
+            "etc/passwd"
+        );
+        assert_eq!(normalize_path("/foo/%2e%2e%2f%2e%2e%2fbar").unwrap(), "bar");
+        assert_eq!(normalize_path("/foo/%2e/bar").unwrap(), "foo/bar");
+    }
+
+    #[test]
+    fn backslashes_separate_segments() {
+        assert_eq!(normalize_path("/..\\..\\x").unwrap(), "x");
+        assert_eq!(
+            normalize_path("/..%5c..%5cWindows%5cwin.ini").unwrap(),
+            "Windows/win.ini"
+        );
+        assert_eq!(normalize_path("/foo\\bar/..\\baz").unwrap(), "foo/baz");
+    }
+
+    #[test]
+    fn nul_is_refused() {
+        assert_eq!(normalize_path("/index.html%00.png"), None);
+        assert_eq!(normalize_path("/dir%00/index.html"), None);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn drives_and_streams_are_refused() {
+        assert_eq!(normalize_path("/C:%5cWindows%5cwin.ini"), None);
+        assert_eq!(normalize_path("/C:/Windows/win.ini"), None);
+        assert_eq!(normalize_path("/file.txt:stream"), None);
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn colons_are_allowed_in_names() {
+        assert_eq!(normalize_path("/12:30.txt").unwrap(), "12:30.txt");
+        assert_eq!(normalize_path("/logs/a:b/c.txt").unwrap(), "logs/a:b/c.txt");
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("with%20space.txt"), "with space.txt");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%2"), "%zz%2");
+        assert_eq!(
+            normalize_path("/with%20space.txt").unwrap(),
+            "with space.txt"
+        );
+    }
+
+    #[test]
+    fn decoded_markup_is_escaped_in_error_pages() {
+        let path = normalize_path("/%3Cimg%20src=x%20onerror=alert(1)%3E").unwrap();
+        assert_eq!(path, "<img src=x onerror=alert(1)>");
+
+        let page = default_error_page("404 NOT FOUND", &format!("page {path} not found"));
+        let page = String::from_utf8(page).unwrap();
+        assert_eq!(
+            page,
+            "<!DOCTYPE html><h1>404: Not found</h1><p>page &lt;img src=x onerror=alert(1)&gt; not found</p>"
+        );
+    }
+
+    /// Request with just the given headers
+    fn with_headers(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            query: None,
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            raw: Vec::new(),
+        }
+    }
+
+    fn client(headers: &[(&str, &str)]) -> Option<IpAddr> {
+        forwarded_client(&with_headers(headers), ProxyHeader::XForwardedFor)
+    }
+
+    fn forwarded(headers: &[(&str, &str)]) -> Option<IpAddr> {
+        forwarded_client(&with_headers(headers), ProxyHeader::Forwarded)
+    }
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn client_from_x_forwarded_for() {
+        assert_eq!(
+            client(&[("X-Forwarded-For", "203.0.113.7")]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client(&[("X-Forwarded-For", "10.0.0.1, 10.0.0.2, 203.0.113.7")]),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client(&[("x-forwarded-for", " 2001:db8::1 ")]),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn client_from_forwarded() {
+        assert_eq!(
+            forwarded(&[("Forwarded", "for=192.0.2.60;proto=http;by=203.0.113.43")]),
+            ip("192.0.2.60")
+        );
+        assert_eq!(
+            forwarded(&[(
+                "Forwarded",
+                "for=10.0.0.1, proto=https;For=\"192.0.2.60:4711\""
+            )]),
+            ip("192.0.2.60")
+        );
+        assert_eq!(
+            forwarded(&[("Forwarded", "for=\"[2001:db8:cafe::17]:4711\"")]),
+            ip("2001:db8:cafe::17")
+        );
+    }
+
+    #[test]
+    fn spoofed_leading_entries_are_ignored() {
+        assert_eq!(
+            client(&[("X-Forwarded-For", "127.0.0.1, 203.0.113.9")]),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            forwarded(&[("Forwarded", "for=127.0.0.1, for=203.0.113.9")]),
+            ip("203.0.113.9")
+        );
+        // a header line sent by the client before the one the proxy added
+        assert_eq!(
+            client(&[
+                ("X-Forwarded-For", "127.0.0.1"),
+                ("X-Forwarded-For", "203.0.113.9")
+            ]),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn only_the_trusted_header_is_used() {
+        // the proxy only adds X-Forwarded-For, so Forwarded is up to the client
+        let headers = [
+            ("Forwarded", "for=127.0.0.1"),
+            ("X-Forwarded-For", "203.0.113.9"),
+        ];
+        assert_eq!(client(&headers), ip("203.0.113.9"));
+        assert_eq!(client(&[("Forwarded", "for=127.0.0.1")]), None);
+
+        // and the other way around
+        let headers = [
+            ("X-Forwarded-For", "127.0.0.1"),
+            ("Forwarded", "for=203.0.113.9"),
+        ];
+        assert_eq!(forwarded(&headers), ip("203.0.113.9"));
+        assert_eq!(forwarded(&[("X-Forwarded-For", "127.0.0.1")]), None);
+    }
+
+    #[test]
+    fn trust_proxy_defaults_to_x_forwarded_for() {
+        assert_eq!(args(&[]).trust_proxy, None);
+        assert_eq!(
+            args(&["--trust-proxy"]).trust_proxy,
+            Some(ProxyHeader::XForwardedFor)
+        );
+        assert_eq!(
+            args(&["--trust-proxy=forwarded"]).trust_proxy,
+            Some(ProxyHeader::Forwarded)
+        );
+
+        // a path after it isn't taken as the header
+        let args = args(&["--trust-proxy", "site"]);
+        assert_eq!(args.trust_proxy, Some(ProxyHeader::XForwardedFor));
+        assert_eq!(args.path, Some(PathBuf::from("site")));
+    }
+
+    #[test]
+    fn no_or_bad_client_address() {
+        assert_eq!(client(&[]), None);
+        assert_eq!(client(&[("X-Forwarded-For", "unknown")]), None);
+        assert_eq!(forwarded(&[("Forwarded", "for=_hidden")]), None);
+    }
+
+    /// Arguments as given on the command line
+    fn args(args: &[&str]) -> Args {
+        Args::parse_from(["http-reloaded"].iter().chain(args))
+    }
+
+    #[test]
+    fn reload_excludes_match_inside_the_watched_directory() {
+        let args = args(&["--reload-exclude", "*.map", "--reload-exclude", "drafts/*"]);
+        let roots = [PathBuf::from("/srv/site")];
+        let excluded = |path: &str| is_reload_excluded(Path::new(path), &roots, &args);
+
+        assert!(excluded("/srv/site/app.js.map"));
+        assert!(excluded("/srv/site/drafts/post.md"));
+        assert!(!excluded("/srv/site/app.js"));
+        assert!(!excluded("/srv/site/posts/drafts/post.md"));
+    }
+
+    #[test]
+    fn reload_excludes_skip_the_path_above_the_root() {
+        // anything matches a pattern starting with * when it includes the path to the root
+        let args = args(&["--reload-exclude", "*/drafts/*"]);
+        let roots = [PathBuf::from("/home/me/drafts/site")];
+        let excluded = |path: &str| is_reload_excluded(Path::new(path), &roots, &args);
+
+        assert!(!excluded("/home/me/drafts/site/index.html"));
+        assert!(excluded("/home/me/drafts/site/posts/drafts/a.md"));
+        assert!(!excluded("/elsewhere/drafts/a.md"));
+    }
+
+    #[test]
+    fn excluded_dirs_are_only_looked_for_inside_the_watched_directory() {
+        let exclude = ["node_modules".to_string(), ".git".to_string()];
+        let roots = [PathBuf::from("/home/me/node_modules/site")];
+        let excluded = |path: &str| is_excluded(Path::new(path), &roots, &exclude);
+
+        assert!(!excluded("/home/me/node_modules/site/index.html"));
+        assert!(excluded("/home/me/node_modules/site/node_modules/a/b.js"));
+        assert!(excluded("/home/me/node_modules/site/.git/HEAD"));
+        assert!(!excluded("/home/me/node_modules/site/modules/a.js"));
+    }
+
+    #[test]
+    fn reload_line_has_paths_relative_to_the_watched_directory() {
+        let roots = [PathBuf::from("/srv/site")];
+        let paths = [
+            Path::new("/srv/site/index.html"),
+            Path::new("/srv/site/css/style.css"),
+            Path::new("/srv/shared/logo.svg"),
+        ];
+        assert_eq!(
+            reload_line(1700000000123, paths.into_iter(), &roots),
+            r#"RELOAD 1700000000123 "index.html" "css/style.css" "/srv/shared/logo.svg""#
+        );
+    }
+
+    #[test]
+    fn reload_line_quotes_paths_with_spaces() {
+        let roots = [PathBuf::from("/srv/site")];
+        let paths = [
+            Path::new("/srv/site/my page.html"),
+            Path::new("/srv/site/say \"hi\".txt"),
+            Path::new("/srv/site/back\\slash.txt"),
+        ];
+        assert_eq!(
+            reload_line(1, paths.into_iter(), &roots),
+            r#"RELOAD 1 "my page.html" "say \"hi\".txt" "back\\slash.txt""#
+        );
+    }
+
+    /// Server for the directory, with the arguments given on the command line
+    fn server(root: &Path, arguments: &[&str]) -> Server {
+        Server {
+            path: root.to_path_buf(),
+            args: args(arguments),
+            site_script: None,
+            browser_url: String::new(),
+            update_notify_script: String::new(),
+            update_notify: Mutex::new(Vec::new()),
+            stats: Stats::default(),
+            header_rules: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Empty directory for a test, cleared again by the next run
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("http-reloaded-test-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn refused_directories_are_not_read_errors() {
+        let dir = temp_dir("read-error");
+        fs::write(dir.join("file.txt"), "hi").unwrap();
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+
+        assert_eq!(read_error_status(&denied, &dir), None);
+        assert_eq!(
+            read_error_status(&denied, &dir.join("file.txt")),
+            Some("403 FORBIDDEN")
+        );
+        assert_eq!(
+            read_error_status(&io::Error::from(io::ErrorKind::NotFound), &dir.join("gone")),
+            None
+        );
+    }
+
+    #[test]
+    fn no_index_page_names_the_directory() {
+        let dir = temp_dir("no-index");
+        fs::create_dir(dir.join("docs")).unwrap();
+        let server = server(&dir, &[]);
+
+        assert_eq!(
+            no_index_page(&server, "docs"),
+            default_error_page("404 NOT FOUND", "No index file in directory /docs")
+        );
+    }
+
+    #[test]
+    fn empty_root_explains_how_to_start() {
+        let dir = temp_dir("empty-root");
+        let server = server(&dir, &[]);
+        let page = String::from_utf8(no_index_page(&server, "")).unwrap();
+
+        assert!(page.contains("is empty. Add an index.html file to it"));
+    }
+
+    #[test]
+    fn index_overrides_are_used_in_their_directory() {
+        let dir = temp_dir("index-override");
+        fs::create_dir(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/index.html"), "index").unwrap();
+        fs::write(dir.join("docs/start.html"), "start").unwrap();
+        let names = ["index.html".to_string()];
+
+        assert_eq!(
+            find_index(&dir.join("docs"), &names),
+            Some(dir.join("docs/index.html"))
+        );
+
+        fs::write(dir.join("docs/.index"), "missing.html\nstart.html\n").unwrap();
+        assert_eq!(
+            find_index(&dir.join("docs"), &names),
+            Some(dir.join("docs/start.html"))
+        );
+    }
+
+    #[test]
+    fn index_overrides_stay_in_their_directory() {
+        let dir = temp_dir("index-escape");
+        fs::create_dir(dir.join("docs")).unwrap();
+        fs::write(dir.join("secret.html"), "secret").unwrap();
+        fs::write(dir.join("docs/index.html"), "index").unwrap();
+        let overrides = format!(
+            "../secret.html\n{}\nsub/../../secret.html\n..\n",
+            dir.join("secret.html").display()
+        );
+        fs::write(dir.join("docs/.index"), overrides).unwrap();
+
+        assert_eq!(
+            find_index(&dir.join("docs"), &["index.html".to_string()]),
+            Some(dir.join("docs/index.html"))
+        );
+    }
+
+    #[test]
+    fn site_out_is_found_before_it_exists() {
+        let roots = [PathBuf::from("/srv/site")];
+
+        assert_eq!(
+            site_out_part(Path::new("/srv/site/dist"), &roots),
+            Some(PathBuf::from("dist"))
+        );
+        assert_eq!(
+            site_out_part(Path::new("/tmp/http-reloaded-1"), &roots),
+            None
+        );
+
+        // and changes in it are told apart from the rest
+        let out = site_out_part(Path::new("/srv/site/dist"), &roots).unwrap();
+        let in_out = |path: &str| {
+            watched_part(Path::new(path), &roots).is_some_and(|path| path.starts_with(&out))
+        };
+        assert!(in_out("/srv/site/dist/index.html"));
+        assert!(!in_out("/srv/site/distant.html"));
+        assert!(!in_out("/srv/site/site.lua"));
+    }
+
+    #[test]
+    fn few_threads_serve_concurrent_requests() {
+        let dir = temp_dir("threads");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let server = Arc::new(server(&dir, &["--threads", "2"]));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let workers = spawn_workers(&server, server.args.threads.unwrap().get());
+        let accepting = server.clone();
+        thread::spawn(move || accept_connections(listener, &accepting, &workers));
+
+        let clients = (0..32)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    stream
+                        .set_read_timeout(Some(Duration::from_secs(10)))
+                        .unwrap();
+                    stream
+                        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                        .unwrap();
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).unwrap();
+                    response
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for client in clients {
+            let response = client.join().unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+        }
+        assert_eq!(server.stats.connections.load(Ordering::Relaxed), 32);
+    }
+
+    #[test]
+    fn header_files_are_hidden_in_any_case() {
+        let dir = temp_dir("hidden");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("x.html"), "x").unwrap();
+
+        assert!(is_hidden(&dir, &dir.join("_headers")));
+        assert!(is_hidden(&dir, &dir.join("_HEADERS")));
+        assert!(is_hidden(&dir, &dir.join("x.html.headers")));
+        assert!(is_hidden(&dir, &dir.join("x.html.HEADERS")));
+        assert!(is_hidden(&dir, &dir.join("x.html.Headers")));
+
+        // only the root has a _headers file, and sidecars need the file they're for
+        assert!(!is_hidden(&dir, &dir.join("sub/_headers")));
+        assert!(!is_hidden(&dir, &dir.join("y.html.headers")));
+        assert!(!is_hidden(&dir, &dir.join("x.html")));
+        assert!(!is_hidden(&dir, &dir.join(".headers")));
+    }
+
+    /// Client side of a connection the request was answered on, for responses that don't end
+    fn connect(server: &Server, request: &str) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        handle_connection(stream, server).unwrap();
+        client
+    }
+
+    /// Response the server sends for the raw request
+    fn respond(server: &Server, request: &str) -> String {
+        let mut client = connect(server, request);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    /// Response to a GET for the path
+    fn get(server: &Server, path: &str) -> String {
+        respond(
+            server,
+            &format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+        )
+    }
+
+    /// Port that was free a moment ago, for addresses that have to be known up front
+    fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// Response to a GET for the path from a listening server, waiting for it to start listening
+    fn fetch(addr: &str, path: &str) -> String {
+        let mut attempts = 0;
+        let mut stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(_) if attempts < 100 => attempts += 1,
+                Err(e) => panic!("{addr}: {e}"),
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// Browser connected to the event stream of the server
+    fn subscribe(server: &Server) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let stream = UpdateClient::new(stream).unwrap();
+        server.update_notify.lock().unwrap().push(stream);
+        client
+    }
+
+    /// Next event sent to a browser, still in its chunk
+    fn next_event(client: &mut TcpStream) -> String {
+        let mut buf = [0; 1024];
+        let len = client.read(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    /// Watch the directory in the background, like when serving
+    fn watch(server: &Arc<Server>, dir: &Path) {
+        let (sender, receiver) = mpsc::channel();
+        let mut debouncer = FileWatcher::new(&server.args, sender).unwrap();
+        debouncer
+            .watcher()
+            .watch(dir, RecursiveMode::Recursive)
+            .unwrap();
+
+        let server = server.clone();
+        let dir = dir.to_path_buf();
+        thread::spawn(move || {
+            watch_changes(&server, debouncer, receiver, &dir, RecursiveMode::Recursive)
+        });
+    }
+
+    /// Handle the changes sent through the channel in the background, like the ones from watching
+    fn changes(server: &Arc<Server>, dir: &Path) -> mpsc::Sender<DebounceEventResult> {
+        let (sender, receiver) = mpsc::channel();
+        let debouncer = FileWatcher::new(&server.args, mpsc::channel().0).unwrap();
+
+        let server = server.clone();
+        let dir = dir.to_path_buf();
+        thread::spawn(move || {
+            watch_changes(&server, debouncer, receiver, &dir, RecursiveMode::Recursive)
+        });
+        sender
+    }
+
+    /// Change to a single file
+    fn change(path: PathBuf) -> DebounceEventResult {
+        Ok(vec![DebouncedEvent {
+            path,
+            kind: DebouncedEventKind::Any,
+        }])
+    }
+
+    #[test]
+    fn charset_is_declared_on_text() {
+        let dir = temp_dir("charset");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        fs::write(dir.join("data.json"), "{}").unwrap();
+        fs::write(dir.join("image.png"), "").unwrap();
+
+        let default = server(&dir, &[]);
+        assert!(
+            get(&default, "/index.html").contains("\r\nContent-Type: text/html; charset=utf-8\r\n")
+        );
+
+        let latin = server(&dir, &["--charset", "iso-8859-1"]);
+        assert!(get(&latin, "/index.html")
+            .contains("\r\nContent-Type: text/html; charset=iso-8859-1\r\n"));
+        assert!(get(&latin, "/data.json").contains("\r\nContent-Type: application/json\r\n"));
+        assert!(get(&latin, "/image.png").contains("\r\nContent-Type: image/png\r\n"));
+    }
+
+    #[test]
+    fn base_url_is_stripped_from_targets() {
+        assert_eq!(strip_base_url("/index.html", ""), Some("/index.html"));
+        assert_eq!(strip_base_url("/index.html", "/"), Some("/index.html"));
+        assert_eq!(
+            strip_base_url("/preview/index.html", "/preview"),
+            Some("/index.html")
+        );
+        assert_eq!(
+            strip_base_url("/preview/index.html", "preview/"),
+            Some("/index.html")
+        );
+        assert_eq!(strip_base_url("//preview/", "/preview"), Some("/"));
+        assert_eq!(strip_base_url("/preview", "/preview"), Some(""));
+
+        // only whole segments, and nothing outside of it
+        assert_eq!(strip_base_url("/previews/index.html", "/preview"), None);
+        assert_eq!(strip_base_url("/index.html", "/preview"), None);
+    }
+
+    #[test]
+    fn pages_are_served_under_the_base_url() {
+        let dir = temp_dir("base-url");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let server = server(&dir, &["--base-url", "/preview"]);
+
+        assert!(get(&server, "/preview/index.html").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(&server, "/preview/").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(&server, "/index.html").starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+    }
+
+    #[test]
+    fn unsupported_methods_are_told_apart_from_unknown_ones() {
+        let dir = temp_dir("methods");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let server = server(&dir, &[]);
+        let request = |method: &str| {
+            respond(
+                &server,
+                &format!("{method} /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            )
+        };
+
+        let post = request("POST");
+        assert!(post.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"));
+        assert!(post.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+
+        let foobar = request("FOOBAR");
+        assert!(foobar.starts_with("HTTP/1.1 501 NOT IMPLEMENTED\r\n"));
+        assert!(!foobar.contains("\r\nAllow:"));
+
+        assert!(request("GET").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn site_paths_are_under_the_base_url() {
+        assert_eq!(site_path("", "/docs/"), "/docs/");
+        assert_eq!(site_path("/", "/docs/"), "/docs/");
+        assert_eq!(site_path("/preview", "/docs/"), "/preview/docs/");
+        assert_eq!(site_path("preview/", "/"), "/preview/");
+    }
+
+    #[test]
+    fn browser_opens_the_open_path() {
+        assert_eq!(
+            browser_url("127.0.0.1:1111", "", "/"),
+            "http://127.0.0.1:1111/"
+        );
+        assert_eq!(
+            browser_url("127.0.0.1:1111", "", "/docs/start.html"),
+            "http://127.0.0.1:1111/docs/start.html"
+        );
+        assert_eq!(
+            browser_url("[::1]:8080", "/preview", "/docs/"),
+            "http://[::1]:8080/preview/docs/"
+        );
+    }
+
+    #[test]
+    fn empty_files_have_the_right_length() {
+        let dir = temp_dir("empty-files");
+        fs::write(dir.join("empty.txt"), "").unwrap();
+        fs::write(dir.join("empty.html"), "").unwrap();
+        let mut server = server(&dir, &[]);
+        server.update_notify_script = update_notify_script(&server.args).unwrap();
+        let script = server.update_notify_script.clone();
+        assert!(!script.is_empty());
+
+        let text = get(&server, "/empty.txt");
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(text.contains("\r\nContent-Length: 0\r\n"));
+        assert!(text.ends_with("\r\n\r\n"));
+
+        // html gets the reload script, and nothing else
+        let html = get(&server, "/empty.html");
+        assert!(html.contains(&format!("\r\nContent-Length: {}\r\n", script.len())));
+        assert!(html.ends_with(&format!("\r\n\r\n{script}")));
+    }
+
+    #[test]
+    fn head_markup_goes_before_the_end_of_the_head() {
+        let markup = "<meta name=\"x\">";
+        assert_eq!(
+            insert_head(b"<html><head><title>t</title></head><body></body>", markup).unwrap(),
+            b"<html><head><title>t</title><meta name=\"x\"></head><body></body>"
+        );
+        assert_eq!(
+            insert_head(b"<HEAD></HEAD>", markup).unwrap(),
+            b"<HEAD><meta name=\"x\"></HEAD>"
+        );
+        assert_eq!(insert_head(b"<p>no head</p>", markup), None);
+    }
+
+    #[test]
+    fn head_markup_is_only_added_to_html() {
+        let dir = temp_dir("inject-head");
+        fs::write(dir.join("page.html"), "<head></head>").unwrap();
+        fs::write(dir.join("notes.txt"), "<head></head>").unwrap();
+        let server = server(&dir, &["--inject-head", "<script src=\"/x.js\"></script>"]);
+
+        let page = "<head><script src=\"/x.js\"></script></head>";
+        let html = get(&server, "/page.html");
+        assert!(html.contains(&format!("\r\nContent-Length: {}\r\n", page.len())));
+        assert!(html.ends_with(&format!("\r\n\r\n{page}")));
+
+        let text = get(&server, "/notes.txt");
+        assert!(text.contains("\r\nContent-Length: 13\r\n"));
+        assert!(text.ends_with("\r\n\r\n<head></head>"));
+    }
+
+    #[test]
+    fn reload_strategies_are_parsed() {
+        assert_eq!(
+            parse_reload_strategy("html=full"),
+            Ok(("html".to_string(), ReloadStrategy::Full))
+        );
+        assert_eq!(
+            parse_reload_strategy(".scss = Style"),
+            Ok(("scss".to_string(), ReloadStrategy::Style))
+        );
+        assert_eq!(
+            parse_reload_strategy("md=ignore"),
+            Ok(("md".to_string(), ReloadStrategy::Ignore))
+        );
+        assert!(parse_reload_strategy("md").is_err());
+        assert!(parse_reload_strategy("md=sometimes").is_err());
+    }
+
+    #[test]
+    fn reload_strategy_follows_the_extension() {
+        let args = args(&[
+            "--reload-strategy",
+            "md=ignore",
+            "--reload-strategy",
+            "scss=style",
+            "--reload-strategy",
+            "css=full",
+        ]);
+        let strategy = |path: &str| reload_strategy(&args, Path::new(path));
+
+        assert_eq!(strategy("/site/notes.MD"), ReloadStrategy::Ignore);
+        assert_eq!(strategy("/site/style.scss"), ReloadStrategy::Style);
+        assert_eq!(strategy("/site/style.css"), ReloadStrategy::Full);
+        assert_eq!(strategy("/site/index.html"), ReloadStrategy::Full);
+        assert_eq!(strategy("/site/Makefile"), ReloadStrategy::Full);
+    }
+
+    #[test]
+    fn only_stylesheets_changing_swaps_them() {
+        let dir = temp_dir("reload-message");
+        for file in ["style.css", "theme.css", "index.html"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let events = |files: &[&str]| {
+            files
+                .iter()
+                .map(|file| DebouncedEvent {
+                    path: dir.join(file),
+                    kind: DebouncedEventKind::Any,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let css_only = args(&["--reload-css-only"]);
+        assert_eq!(
+            reload_message(&css_only, &events(&["style.css", "theme.css"])),
+            "css"
+        );
+        assert_eq!(
+            reload_message(&css_only, &events(&["style.css", "index.html"])),
+            "update"
+        );
+        assert_eq!(
+            reload_message(&css_only, &events(&["style.css", "gone.css"])),
+            "delete"
+        );
+
+        // without the flag stylesheets reload the page like anything else
+        assert_eq!(
+            reload_message(&args(&[]), &events(&["style.css"])),
+            "update"
+        );
+    }
+
+    #[test]
+    fn explicit_mime_types_win_over_the_mime_file() {
+        let dir = temp_dir("mime-file");
+        let file = dir.join("mime.types");
+        fs::write(
+            &file,
+            "# comment\ntext/x-file md markdown\n\ntext/x-file-txt txt # trailing\n",
+        )
+        .unwrap();
+        let mime_file = file.to_str().unwrap();
+        let overrides = mime_overrides(&args(&[
+            "--mime",
+            "txt=text/x-explicit",
+            "--mime-file",
+            mime_file,
+        ]))
+        .unwrap();
+        let mime = |path: &str| find_mime_type(&overrides, Path::new(path));
+
+        assert_eq!(mime("notes.txt"), Some("text/x-explicit"));
+        assert_eq!(mime("notes.md"), Some("text/x-file"));
+        assert_eq!(mime("notes.markdown"), Some("text/x-file"));
+        assert_eq!(mime("index.html"), Some("text/html"));
+        assert_eq!(mime("notes.unknown"), None);
+    }
+
+    #[test]
+    fn missing_mime_file_is_an_error() {
+        let dir = temp_dir("mime-file-missing");
+        let missing = dir.join("mime.types");
+        assert!(mime_overrides(&args(&["--mime-file", missing.to_str().unwrap()])).is_err());
+    }
+
+    /// Cors headers for a request from the origin, as pairs of strings
+    fn cors(args: &Args, origin: &str) -> Vec<(&'static str, String)> {
+        cors_headers(args, &with_headers(&[("Origin", origin)]))
+    }
+
+    #[test]
+    fn exact_origins_are_echoed_with_credentials() {
+        let args = args(&["--cors-origin", "https://app.example/"]);
+        assert_eq!(
+            cors(&args, "https://app.example"),
+            [
+                (
+                    "Access-Control-Allow-Origin",
+                    "https://app.example".to_string()
+                ),
+                ("Access-Control-Allow-Credentials", "true".to_string()),
+                ("Vary", "Origin".to_string()),
+            ]
+        );
+        assert!(cors(&args, "https://evil.example").is_empty());
+        assert!(cors_headers(&args, &with_headers(&[])).is_empty());
+    }
+
+    #[test]
+    fn any_origin_is_allowed_without_credentials() {
+        let any = args(&["--cors-origin", "*"]);
+        assert_eq!(
+            cors(&any, "https://anything.example"),
+            [("Access-Control-Allow-Origin", "*".to_string())]
+        );
+        assert!(cors(&args(&[]), "https://anything.example").is_empty());
+    }
+
+    /// Preflight from the origin, asking for a PUT with the headers
+    fn preflight(server: &Server, request_headers: Option<&str>) -> String {
+        let mut request = String::from(
+            "OPTIONS /api HTTP/1.1\r\nOrigin: https://app.example\r\nAccess-Control-Request-Method: PUT\r\n",
+        );
+        if let Some(headers) = request_headers {
+            request.push_str(&format!("Access-Control-Request-Headers: {headers}\r\n"));
+        }
+        request.push_str("\r\n");
+        respond(server, &request)
+    }
+
+    #[test]
+    fn preflights_get_the_configured_methods_and_headers() {
+        let dir = temp_dir("preflight");
+        let server = server(
+            &dir,
+            &[
+                "--cors-origin",
+                "https://app.example",
+                "--cors-methods",
+                "GET,PUT",
+                "--cors-methods",
+                "DELETE",
+                "--cors-headers",
+                "Content-Type,X-Token",
+            ],
+        );
+
+        let response = preflight(&server, Some("X-Other"));
+        assert!(response.starts_with("HTTP/1.1 204 NO CONTENT\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET, PUT, DELETE\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Headers: Content-Type, X-Token\r\n"));
+        assert!(!response.contains("X-Other"));
+    }
+
+    #[test]
+    fn preflights_allow_the_requested_headers_by_default() {
+        let dir = temp_dir("preflight-default");
+        let server = server(&dir, &["--cors-origin", "https://app.example"]);
+
+        let response = preflight(&server, Some("X-Token, Content-Type"));
+        assert!(response.starts_with("HTTP/1.1 204 NO CONTENT\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Headers: X-Token, Content-Type\r\n"));
+
+        let response = preflight(&server, None);
+        assert!(!response.contains("Access-Control-Allow-Headers"));
+    }
+
+    #[test]
+    fn preflights_from_other_origins_get_no_cors_headers() {
+        let dir = temp_dir("preflight-other");
+        let server = server(&dir, &["--cors-origin", "https://other.example"]);
+
+        let response = preflight(&server, None);
+        assert!(!response.contains("Access-Control-"));
+    }
+
+    #[test]
+    fn site_is_built_into_out() {
+        let dir = temp_dir("site-out");
+        fs::write(
+            dir.join("site.lua"),
+            "return { [\"index.html\"] = \"built\" }",
+        )
+        .unwrap();
+        let out = dir.join("dist");
+        let out_arg = out.to_str().unwrap();
+
+        let mut site = server(&out, &["--out", out_arg]);
+        site.site_script = Some(dir.join("site.lua"));
+        site.build_site().unwrap();
+
+        assert_eq!(fs::read_to_string(out.join("index.html")).unwrap(), "built");
+        assert!(get(&site, "/").ends_with("built"));
+
+        // it's not temporary, so it stays
+        site.remove_temp_out();
+        assert!(out.join("index.html").is_file());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn passed_in_socket_is_served() {
+        use std::os::fd::{AsRawFd, IntoRawFd};
+
+        let dir = temp_dir("listen-fd");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let server = Arc::new(server(&dir, &[]));
+
+        // passed in as nonblocking, and without close on exec
+        let socket =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        socket
+            .bind(
+                &"127.0.0.1:0"
+                    .parse::<std::net::SocketAddr>()
+                    .unwrap()
+                    .into(),
+            )
+            .unwrap();
+        socket.listen(8).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket.set_cloexec(false).unwrap();
+        let listener = listen_fd(TcpListener::from(socket).into_raw_fd()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(!socket2::SockRef::from(&listener).nonblocking().unwrap());
+
+        // O_CLOEXEC, shown in octal
+        #[cfg(target_os = "linux")]
+        {
+            let info =
+                fs::read_to_string(format!("/proc/self/fdinfo/{}", listener.as_raw_fd())).unwrap();
+            let flags = info
+                .lines()
+                .find_map(|line| line.strip_prefix("flags:"))
+                .unwrap();
+            assert!(
+                u32::from_str_radix(flags.trim(), 8).unwrap() & 0o2000000 != 0,
+                "{info}"
+            );
+        }
+
+        let workers = spawn_workers(&server, 1);
+        let accepting = server.clone();
+        thread::spawn(move || accept_connections(listener, &accepting, &workers));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+    }
+
+    #[test]
+    fn editing_the_site_script_rebuilds_and_reloads() {
+        let dir = temp_dir("site-edit");
+        let script = dir.join("site.lua");
+        fs::write(&script, "return { [\"index.html\"] = \"before\" }").unwrap();
+        let out = dir.join("dist");
+
+        let mut site = server(&out, &["--out", out.to_str().unwrap(), "--debounce", "50"]);
+        site.site_script = Some(script.clone());
+        site.build_site().unwrap();
+        let site = Arc::new(site);
+        let mut browser = subscribe(&site);
+        watch(&site, &dir);
+
+        fs::write(&script, "return { [\"index.html\"] = \"after\" }").unwrap();
+        assert!(next_event(&mut browser).contains("data: update\n\n"));
+        assert!(get(&site, "/").ends_with("after"));
+    }
+
+    #[test]
+    fn files_in_new_directories_reload() {
+        let dir = temp_dir("new-dir");
+        let site = Arc::new(server(&dir, &["--debounce", "50"]));
+        let mut browser = subscribe(&site);
+        watch(&site, &dir);
+
+        // the directory itself changes the listing, so let that reload pass first
+        fs::create_dir(dir.join("posts")).unwrap();
+        assert!(next_event(&mut browser).contains("id: 1\n"));
+
+        fs::write(dir.join("posts/new.html"), "new").unwrap();
+        assert!(next_event(&mut browser).contains("id: 2\ndata: update\n\n"));
+    }
+
+    #[test]
+    fn directory_without_slash_serves_its_index() {
+        let dir = temp_dir("dir-index");
+        fs::create_dir(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/index.html"), "docs").unwrap();
+        let site = server(&dir, &[]);
+
+        let response = get(&site, "/docs");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\ndocs"), "{response}");
+        assert_eq!(get(&site, "/docs/"), response);
+    }
+
+    #[test]
+    fn http2_preface_is_told_to_use_http1() {
+        let dir = temp_dir("http2");
+        let response = respond(&server(&dir, &[]), "PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n");
+        let response = response.as_bytes();
+        assert_eq!(response, HTTP2_REJECT);
+
+        // empty SETTINGS, then a GOAWAY for stream 0 with HTTP_1_1_REQUIRED
+        assert_eq!(response[..9], [0, 0, 0, 4, 0, 0, 0, 0, 0]);
+        assert_eq!(response[9..18], [0, 0, 8, 7, 0, 0, 0, 0, 0]);
+        assert_eq!(response[22..], [0, 0, 0, 0xd]);
+    }
+
+    #[test]
+    fn urls_over_the_limit_are_refused() {
+        let dir = temp_dir("uri-length");
+        let name = "a".repeat(99);
+        fs::write(dir.join(&name), "a").unwrap();
+        let site = server(&dir, &["--max-uri-length", "100"]);
+
+        let response = get(&site, &format!("/{name}"));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        let response = get(&site, &format!("/{name}?"));
+        assert!(
+            response.starts_with("HTTP/1.1 414 URI TOO LONG\r\n"),
+            "{response}"
+        );
+        let response = get(&site, &format!("/{name}a"));
+        assert!(
+            response.starts_with("HTTP/1.1 414 URI TOO LONG\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn options_on_a_page_lists_the_methods() {
+        let dir = temp_dir("options");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        fs::create_dir(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/index.html"), "docs").unwrap();
+        let site = server(&dir, &[]);
+        let options = |path: &str| {
+            respond(
+                &site,
+                &format!("OPTIONS {path} HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+            )
+        };
+
+        for path in ["/index.html", "/", "/docs"] {
+            let response = options(path);
+            assert!(
+                response.starts_with("HTTP/1.1 204 NO CONTENT\r\n"),
+                "{response}"
+            );
+            assert!(
+                response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"),
+                "{response}"
+            );
+            assert!(response.ends_with("\r\n\r\n"), "{response}");
+        }
+
+        let response = options("/missing.html");
+        assert!(
+            response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"),
+            "{response}"
+        );
+        assert!(!response.contains("\r\nAllow:"), "{response}");
+    }
+
+    #[test]
+    fn options_on_the_server_lists_the_methods() {
+        let dir = temp_dir("options-server");
+        let response = respond(
+            &server(&dir, &[]),
+            "OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 204 NO CONTENT\r\n"),
+            "{response}"
+        );
+        assert!(
+            response.contains("\r\nAllow: GET, HEAD, OPTIONS\r\n"),
+            "{response}"
+        );
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+    }
+
+    #[test]
+    fn unknown_paths_serve_the_catch_all() {
+        let dir = temp_dir("catch-all");
+        fs::write(dir.join("app.html"), "app").unwrap();
+        fs::create_dir(dir.join("empty")).unwrap();
+        let app = dir.join("app.html");
+        let site = server(&dir, &["--catch-all", app.to_str().unwrap()]);
+
+        let response = get(&site, "/some/route");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\napp"), "{response}");
+
+        // a directory is there, it just has no index
+        let response = get(&site, "/empty/");
+        assert!(
+            response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"),
+            "{response}"
+        );
+        assert!(!response.contains("\r\n\r\napp"), "{response}");
+    }
+
+    #[test]
+    fn files_named_like_reserved_paths_are_not_served() {
+        let dir = temp_dir("reserved");
+        fs::write(dir.join("__status"), "file").unwrap();
+        fs::write(dir.join("__health"), "file").unwrap();
+        let site = server(&dir, &["--health-path", "__health"]);
+
+        let response = get(&site, "/__status");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("application/json"), "{response}");
+        assert!(!response.ends_with("file"), "{response}");
+        assert!(get(&site, "/__health").ends_with("\r\n\r\nok"));
+    }
+
+    #[test]
+    fn health_answers_without_an_index() {
+        let dir = temp_dir("health");
+        let site = server(&dir, &[]);
+        assert!(get(&site, "/").starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+
+        let response = get(&site, "/__health");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nok"), "{response}");
+
+        let site = server(&dir, &["--health-path", "/ready"]);
+        assert!(get(&site, "/ready").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(&site, "/__health").starts_with("HTTP/1.1 404 NOT FOUND\r\n"));
+    }
+
+    #[test]
+    fn event_streams_are_listed_as_clients() {
+        let dir = temp_dir("clients");
+        let site = server(&dir, &[]);
+        assert!(get(&site, "/__clients").ends_with("{\"count\":0,\"clients\":[]}"));
+
+        let request = format!("GET /{VERY_LONG_PATH} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let browser = connect(&site, &request);
+        let peer = browser.local_addr().unwrap();
+        let response = get(&site, "/__clients");
+        assert!(
+            response.ends_with(&format!("{{\"count\":1,\"clients\":[\"{peer}\"]}}")),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn files_are_listed_a_page_at_a_time() {
+        let dir = temp_dir("files");
+        fs::create_dir(dir.join("css")).unwrap();
+        fs::create_dir(dir.join(".git")).unwrap();
+        for file in [
+            "index.html",
+            "about.html",
+            "about.html.headers",
+            "css/site.css",
+            ".env",
+            ".git/config",
+            "_headers",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        let site = server(&dir, &[]);
+        let files = |query: &str| {
+            let response = get(&site, &format!("/__files{query}"));
+            response.split_once("\r\n\r\n").unwrap().1.to_string()
+        };
+
+        assert_eq!(
+            files(""),
+            r#"["/about.html","/css/site.css","/index.html"]"#
+        );
+        assert_eq!(files("?limit=2"), r#"["/about.html","/css/site.css"]"#);
+        assert_eq!(files("?offset=2&limit=2"), r#"["/index.html"]"#);
+        assert_eq!(files("?offset=3"), "[]");
+
+        // never more than --files-limit at once
+        let site = server(&dir, &["--files-limit", "1"]);
+        assert!(get(&site, "/__files?limit=5").ends_with(r#"["/about.html"]"#));
+    }
+
+    #[test]
+    fn reloads_are_numbered() {
+        let dir = temp_dir("generations");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let site = Arc::new(server(&dir, &[]));
+        let mut browser = subscribe(&site);
+        let changes = changes(&site, &dir);
+
+        for generation in 1..=3 {
+            changes.send(change(dir.join("index.html"))).unwrap();
+            let event = next_event(&mut browser);
+            assert!(
+                event.contains(&format!("\nid: {generation}\ndata: update\n\n")),
+                "{event}"
+            );
+        }
+
+        // browsers that connect now start from the last one
+        let request = format!("GET /{VERY_LONG_PATH} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let mut browser = connect(&site, &request);
+        let event = next_event(&mut browser);
+        assert!(event.contains("\nid: 3\ndata: initial\n\n"), "{event}");
+    }
+
+    #[test]
+    fn unknown_types_are_refused_when_strict() {
+        let dir = temp_dir("strict-mime");
+        fs::write(dir.join("data.unknownext"), "data").unwrap();
+        fs::write(dir.join("index.html"), "hi").unwrap();
+
+        let strict = server(&dir, &["--strict-mime"]);
+        let response = get(&strict, "/data.unknownext");
+        assert!(
+            response.starts_with("HTTP/1.1 415 UNSUPPORTED MEDIA TYPE\r\n"),
+            "{response}"
+        );
+        assert!(get(&strict, "/index.html").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let response = get(&server(&dir, &[]), "/data.unknownext");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\ndata"), "{response}");
+    }
+
+    #[test]
+    fn localhost_only_refuses_other_peers() {
+        let args = args(&["--localhost-only"]);
+        let allowed = |peer: &str| is_allowed(&args, &peer.parse().unwrap());
+
+        assert!(allowed("127.0.0.1"));
+        assert!(allowed("::1"));
+        assert!(allowed("::ffff:127.0.0.1"));
+        assert!(!allowed("192.168.1.5"));
+        assert!(!allowed("::ffff:192.168.1.5"));
+        assert!(!allowed("2001:db8::1"));
+    }
+
+    #[test]
+    fn allow_and_deny_ranges() {
+        let ranges = args(&[
+            "--allow",
+            "10.0.0.0/8",
+            "--allow",
+            "::1/128",
+            "--deny",
+            "10.0.5.0/24",
+        ]);
+        let allowed = |peer: &str| is_allowed(&ranges, &peer.parse().unwrap());
+
+        assert!(allowed("10.1.2.3"));
+        assert!(allowed("::1"));
+        assert!(!allowed("192.168.1.5"));
+        assert!(!allowed("127.0.0.1"));
+
+        // deny wins
+        assert!(!allowed("10.0.5.7"));
+
+        // with only a deny everyone else is allowed
+        let deny_only = args(&["--deny", "192.168.0.0/16"]);
+        assert!(!is_allowed(&deny_only, &"192.168.1.5".parse().unwrap()));
+        assert!(is_allowed(&deny_only, &"10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn error_pages_come_from_the_error_page_dir() {
+        let dir = temp_dir("error-pages");
+        fs::create_dir(dir.join("errors")).unwrap();
+        fs::write(dir.join("errors/500.html"), "custom 500").unwrap();
+        fs::write(dir.join("errors/405.html"), "custom 405").unwrap();
+        let errors = dir.join("errors");
+        let site = server(&dir, &["--error-page-dir", errors.to_str().unwrap()]);
+
+        let status = "500 INTERNAL SERVER ERROR";
+        assert_eq!(error_page(&site, status, "broken"), b"custom 500");
+
+        // without a page for the status, the built in one is used
+        let status = "403 FORBIDDEN";
+        assert_eq!(
+            error_page(&site, status, "not yours"),
+            default_error_page(status, "not yours")
+        );
+
+        let response = respond(&site, "POST / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("\r\n\r\ncustom 405"), "{response}");
+    }
+
+    #[test]
+    fn clean_urls_serve_the_html_file() {
+        let dir = temp_dir("clean-urls");
+        fs::write(dir.join("about.html"), "about").unwrap();
+        fs::create_dir(dir.join("blog")).unwrap();
+        fs::write(dir.join("blog.html"), "blog page").unwrap();
+        fs::write(dir.join("blog/index.html"), "blog index").unwrap();
+
+        let site = server(&dir, &["--clean-urls"]);
+        let response = get(&site, "/about");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\nabout"), "{response}");
+
+        // a directory with the name wins
+        assert!(get(&site, "/blog").contains("\r\n\r\nblog index"));
+
+        let response = get(&server(&dir, &[]), "/about");
+        assert!(
+            response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn root_redirects_to_the_start_page() {
+        let dir = temp_dir("index-redirect");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let site = server(&dir, &["--index-redirect", "/docs/intro.html"]);
+
+        let response = get(&site, "/?lang=en");
+        assert!(response.starts_with("HTTP/1.1 302 FOUND\r\n"), "{response}");
+        assert!(
+            response.contains("\r\nLocation: /docs/intro.html?lang=en\r\n"),
+            "{response}"
+        );
+        let response = get(&site, "/");
+        assert!(
+            response.contains("\r\nLocation: /docs/intro.html\r\n"),
+            "{response}"
+        );
+
+        // only the root is sent on
+        assert!(get(&site, "/index.html").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let site = server(&dir, &["--index-redirect", "/docs/", "--base-url", "/app"]);
+        let response = get(&site, "/app/");
+        assert!(
+            response.contains("\r\nLocation: /app/docs/\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn root_index_is_only_for_the_root() {
+        let dir = temp_dir("root-index");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("home.html"), "home").unwrap();
+        fs::write(dir.join("index.html"), "index").unwrap();
+        fs::write(dir.join("sub/home.html"), "sub home").unwrap();
+        fs::write(dir.join("sub/index.html"), "sub index").unwrap();
+        let site = server(&dir, &["--root-index", "home.html"]);
+
+        assert!(get(&site, "/").contains("\r\n\r\nhome"));
+        assert!(get(&site, "/sub/").contains("\r\n\r\nsub index"));
+        assert!(get(&site, "/index.html").contains("\r\n\r\nindex"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_index_is_served_only_inside_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("index-links");
+        let outside = temp_dir("index-links-outside");
+        fs::write(outside.join("index.html"), "outside").unwrap();
+        fs::write(dir.join("page.html"), "inside").unwrap();
+        fs::create_dir(dir.join("in")).unwrap();
+        fs::create_dir(dir.join("out")).unwrap();
+        symlink(dir.join("page.html"), dir.join("in/index.html")).unwrap();
+        symlink(outside.join("index.html"), dir.join("out/index.html")).unwrap();
+        let site = server(&dir, &["--confine-symlinks"]);
+
+        let response = get(&site, "/in/");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\ninside"), "{response}");
+
+        let response = get(&site, "/out/");
+        assert!(
+            response.starts_with("HTTP/1.1 403 FORBIDDEN\r\n"),
+            "{response}"
+        );
+        assert!(!response.contains("outside"), "{response}");
+    }
+
+    #[test]
+    fn script_gets_the_client_options() {
+        let script = update_notify_script(&args(&[])).unwrap();
+        assert!(script.contains("const log = false ?"), "{script}");
+        assert!(script.contains("setTimeout(connect, 3000)"), "{script}");
+
+        let script =
+            update_notify_script(&args(&["--reconnect-interval", "50", "--client-log"])).unwrap();
+        assert!(script.contains("const log = true ?"), "{script}");
+        assert!(script.contains("setTimeout(connect, 50)"), "{script}");
+        assert!(!script.contains("{reconnect_interval}"), "{script}");
+    }
+
+    #[test]
+    fn reload_script_replaces_the_default() {
+        let dir = temp_dir("reload-script");
+        let file = dir.join("reload.html");
+        fs::write(&file, "<script>new EventSource(\"{update_url}\")</script>").unwrap();
+
+        let script = update_notify_script(&args(&["--reload-script", file.to_str().unwrap()]));
+        assert_eq!(
+            script.unwrap(),
+            format!("<script>new EventSource(\"/{VERY_LONG_PATH}\")</script>")
+        );
+
+        let missing = dir.join("missing.html");
+        let script = update_notify_script(&args(&["--reload-script", missing.to_str().unwrap()]));
+        assert!(script.is_err());
+    }
+
+    #[test]
+    fn default_favicon_is_only_for_sites_without_one() {
+        let dir = temp_dir("favicon");
+        let site = server(&dir, &["--default-favicon"]);
+
+        let response = get(&site, "/favicon.ico");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(
+            response.contains("\r\nContent-Type: image/vnd.microsoft.icon\r\n"),
+            "{response}"
+        );
+        let length = format!("\r\nContent-Length: {}\r\n", DEFAULT_FAVICON.len());
+        assert!(response.contains(&length), "{response}");
+
+        fs::write(dir.join("favicon.ico"), "own").unwrap();
+        assert!(get(&site, "/favicon.ico").ends_with("\r\n\r\nown"));
+
+        fs::remove_file(dir.join("favicon.ico")).unwrap();
+        let response = get(&server(&dir, &[]), "/favicon.ico");
+        assert!(
+            response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn no_default_headers_leaves_only_the_given_ones() {
+        let dir = temp_dir("no-default-headers");
+        fs::write(dir.join("page.txt"), "hi").unwrap();
+        let site = server(&dir, &["--no-default-headers", "--header", "X-Test: 1"]);
+
+        assert_eq!(
+            get(&site, "/page.txt"),
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Test: 1\r\n\r\nhi"
+        );
+    }
+
+    #[test]
+    fn misses_are_empty_without_a_404_page() {
+        let dir = temp_dir("no-404-page");
+        let mut site = server(&dir, &["--no-404-page"]);
+        site.update_notify_script = update_notify_script(&site.args).unwrap();
+
+        let response = get(&site, "/missing.html");
+        assert!(
+            response.starts_with("HTTP/1.1 404 NOT FOUND\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("\r\nContent-Length: 0\r\n"), "{response}");
+        assert!(!response.contains("Content-Type"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+    }
+
+    #[test]
+    fn coep_sends_both_isolation_headers() {
+        let dir = temp_dir("coep");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+
+        let response = get(&server(&dir, &["--coep"]), "/");
+        assert!(
+            response.contains("\r\nCross-Origin-Opener-Policy: same-origin\r\n"),
+            "{response}"
+        );
+        assert!(
+            response.contains("\r\nCross-Origin-Embedder-Policy: require-corp\r\n"),
+            "{response}"
+        );
+
+        let response = get(&server(&dir, &[]), "/");
+        assert!(!response.contains("Cross-Origin"), "{response}");
+    }
+
+    #[test]
+    fn nosniff_adds_only_its_header() {
+        let dir = temp_dir("nosniff");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let plain = get(&server(&dir, &[]), "/");
+        let nosniff = get(&server(&dir, &["--nosniff"]), "/");
+
+        let mut added = nosniff
+            .lines()
+            .filter(|line| !plain.lines().any(|l| l == *line));
+        assert_eq!(added.next(), Some("X-Content-Type-Options: nosniff"));
+        assert_eq!(added.next(), None);
+        assert_eq!(nosniff.lines().count(), plain.lines().count() + 1);
+    }
+
+    #[test]
+    fn server_timing_has_read_and_build() {
+        let dir = temp_dir("server-timing");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+
+        let response = get(&server(&dir, &["--server-timing"]), "/");
+        let timing = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Server-Timing: "))
+            .unwrap();
+        let (read, build) = timing.split_once(", ").unwrap();
+        let duration = |metric: &str, name: &str| {
+            let dur = metric
+                .strip_prefix(name)
+                .unwrap()
+                .strip_prefix(";dur=")
+                .unwrap();
+            assert_eq!(dur.split_once('.').unwrap().1.len(), 3, "{timing}");
+            dur.parse::<f64>().unwrap()
+        };
+        assert!(
+            duration(read, "read") <= duration(build, "build"),
+            "{timing}"
+        );
+
+        assert!(!get(&server(&dir, &[]), "/").contains("Server-Timing"));
+    }
+
+    #[test]
+    fn event_stream_asks_proxies_not_to_buffer() {
+        let dir = temp_dir("sse-no-buffer");
+        let request = format!("GET /{VERY_LONG_PATH} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        let mut browser = connect(&server(&dir, &["--sse-no-buffer"]), &request);
+        let head = next_event(&mut browser);
+        assert!(
+            head.contains("\r\nContent-Type: text/event-stream\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nX-Accel-Buffering: no\r\n"), "{head}");
+        assert!(
+            head.contains("\r\nContent-Encoding: identity\r\n"),
+            "{head}"
+        );
+
+        let mut browser = connect(&server(&dir, &[]), &request);
+        let head = next_event(&mut browser);
+        assert!(!head.contains("X-Accel-Buffering"), "{head}");
+    }
+
+    #[test]
+    fn overridden_types_get_a_charset() {
+        let overrides = mime_overrides(&args(&["--mime", "js=application/javascript"])).unwrap();
+        let mime = find_mime_type(&overrides, Path::new("app.js")).unwrap();
+        assert_eq!(mime, "application/javascript");
+        assert_eq!(
+            content_type(mime, "utf-8"),
+            "application/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            content_type("application/json", "utf-8"),
+            "application/json"
+        );
+    }
+
+    #[test]
+    fn stats_count_served_requests() {
+        let dir = temp_dir("stats");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let site = server(&dir, &[]);
+
+        let sent = get(&site, "/").len() + get(&site, "/missing").len();
+        assert_eq!(site.stats.requests.load(Ordering::Relaxed), 2);
+        assert_eq!(site.stats.bytes_sent.load(Ordering::Relaxed), sent as u64);
+
+        // the status page shows the counts from before it
+        let status = get(&site, "/__status");
+        let json = format!(
+            "{{\"connections\":0,\"requests\":3,\"bytes_sent\":{sent},\"reload_clients\":0,\"generation\":0}}"
+        );
+        assert!(status.ends_with(&json), "{status}");
+
+        // and counts the status page itself afterwards
+        let total = sent + status.len();
+        assert_eq!(
+            site.stats.to_json(2),
+            format!("{{\"connections\":0,\"requests\":3,\"bytes_sent\":{total},\"reload_clients\":2,\"generation\":0}}")
+        );
+    }
+
+    #[test]
+    fn every_address_is_served() {
+        let dir = temp_dir("addresses");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let site = Arc::new(server(&dir, &["--threads", "2"]));
+        let addrs = [
+            format!("127.0.0.1:{}", free_port()),
+            format!("127.0.0.1:{}", free_port()),
+        ];
+
+        let listening = addrs.clone();
+        thread::spawn(move || listen(&site, &listening, None));
+        for addr in &addrs {
+            let response = fetch(addr, "/");
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+        }
+    }
+
+    #[test]
+    fn binding_with_a_backlog() {
+        for backlog in ["1", "16", "100000"] {
+            let listener = bind("127.0.0.1:0", &args(&["--backlog", backlog])).unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // connections wait in the backlog until they're accepted
+            let _client = TcpStream::connect(addr).unwrap();
+            let (_, peer) = listener.accept().unwrap();
+            assert!(peer.ip().is_loopback());
+        }
+    }
+
+    #[test]
+    fn rebinding_right_after_closing() {
+        let args = args(&[]);
+        let listener = bind("127.0.0.1:0", &args).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the server closing first leaves the connection in TIME_WAIT on its side
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        drop(stream);
+        let _ = client.read(&mut [0; 1]);
+        drop(client);
+        drop(listener);
+
+        let listener = bind(&addr.to_string(), &args).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn bind_retry_waits_for_the_port() {
+        let held = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = held.local_addr().unwrap().to_string();
+
+        let error = bind_with_retry(&addr, &args(&[])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        // let go of it while retrying
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(held);
+        });
+        let listener = bind_with_retry(&addr, &args(&["--bind-retry", "5"])).unwrap();
+        assert_eq!(listener.local_addr().unwrap().to_string(), addr);
+    }
+
+    #[test]
+    fn accept_errors_that_pass_are_transient() {
+        assert!(is_transient_accept_error(
+            &io::ErrorKind::Interrupted.into()
+        ));
+        assert!(is_transient_accept_error(
+            &io::ErrorKind::ConnectionAborted.into()
+        ));
+
+        // out of file descriptors
+        #[cfg(unix)]
+        assert!(is_transient_accept_error(&io::Error::from_raw_os_error(24)));
+        #[cfg(windows)]
+        assert!(is_transient_accept_error(&io::Error::from_raw_os_error(
+            10024
+        )));
+
+        assert!(!is_transient_accept_error(
+            &io::ErrorKind::InvalidInput.into()
+        ));
+        assert!(!is_transient_accept_error(
+            &io::ErrorKind::PermissionDenied.into()
+        ));
+        assert!(!is_transient_accept_error(&io::ErrorKind::Other.into()));
+    }
+
+    #[test]
+    fn served_connections_send_right_away() {
+        let dir = temp_dir("nodelay");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+
+        for (arguments, nodelay) in [(&[][..], true), (&["--no-nodelay"][..], false)] {
+            let site = server(&dir, arguments);
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+
+            let (stream, _) = listener.accept().unwrap();
+            let served = stream.try_clone().unwrap();
+            handle_connection(stream, &site).unwrap();
+            assert_eq!(served.nodelay().unwrap(), nodelay);
+        }
+    }
+
+    #[test]
+    fn client_leaving_mid_response() {
+        let dir = temp_dir("client-left");
+        fs::write(dir.join("big.bin"), vec![0; 16 * 1024 * 1024]).unwrap();
+        let site = Arc::new(server(&dir, &[]));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let serving = site.clone();
+        let served = thread::spawn(move || handle_connection(stream, &serving));
+
+        // take the start of the response, then reset the connection
+        client.read_exact(&mut [0; 1024]).unwrap();
+        socket2::SockRef::from(&client)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(client);
+
+        let error = served.join().unwrap().unwrap_err();
+        assert!(client_left(&error), "{error:?}");
+        assert!(!client_left(&anyhow::anyhow!("something else")));
+        assert!(!client_left(
+            &io::Error::from(io::ErrorKind::NotFound).into()
+        ));
+    }
+
+    #[test]
+    fn refused_requests_are_logged_with_the_peer_ip() {
+        let peer = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            refused_line(&peer, "POST /form", "405 METHOD NOT ALLOWED"),
+            "[WARN] 127.0.0.1 POST /form: 405 METHOD NOT ALLOWED"
+        );
+
+        // heads that are too large have no request to show, but the peer looks the same
+        let too_large = TooLarge {
+            line_too_long: false,
+        };
+        assert_eq!(
+            refused_line(
+                &peer,
+                &too_large.to_string(),
+                "431 REQUEST HEADER FIELDS TOO LARGE"
+            ),
+            "[WARN] 127.0.0.1 request head is too large: 431 REQUEST HEADER FIELDS TOO LARGE"
+        );
+
+        let dir = temp_dir("refused");
+        let response = respond(
+            &server(&dir, &[]),
+            "POST /form HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 405 METHOD NOT ALLOWED\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn event_stream_ignores_query_and_trailing_slash() {
+        let dir = temp_dir("event-stream-url");
+        let site = server(&dir, &[]);
+
+        for target in [
+            format!("/{VERY_LONG_PATH}"),
+            format!("/{VERY_LONG_PATH}?t=123"),
+            format!("/{VERY_LONG_PATH}/"),
+        ] {
+            let request = format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            let head = next_event(&mut connect(&site, &request));
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+            assert!(
+                head.contains("\r\nContent-Type: text/event-stream\r\n"),
+                "{head}"
+            );
+        }
+        assert_eq!(site.update_notify.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn closed_clients_are_reaped_between_reloads() {
+        let dir = temp_dir("reap");
+        let site = server(&dir, &[]);
+        let _open = subscribe(&site);
+        let closed = subscribe(&site);
+
+        site.reap_clients();
+        assert_eq!(site.update_notify.lock().unwrap().len(), 2);
+
+        drop(closed);
+        site.reap_clients();
+        assert_eq!(site.update_notify.lock().unwrap().len(), 1);
+        assert_eq!(site.stats.generation.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn dry_run_shows_the_root_without_binding() {
+        let dir = temp_dir("dry-run");
+        let addrs = [format!("127.0.0.1:{}", free_port())];
+        let addr = &addrs[0];
+        let path = dir.to_str().unwrap();
+        let arguments = args(&[path, "--address", addr, "--dry-run"]);
+
+        let summary = dry_run_summary(
+            &arguments,
+            Some(&dir.join(".")),
+            &dir,
+            RecursiveMode::Recursive,
+            &addrs,
+            None,
+        )
+        .unwrap();
+        let root = fs::canonicalize(&dir).unwrap();
+        assert!(
+            summary.starts_with(&format!("root: {}\n", root.display())),
+            "{summary}"
+        );
+        assert!(
+            summary.contains(&format!("\naddress: {addr}\n")),
+            "{summary}"
+        );
+
+        serve(arguments).unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failing_start_command_stops_before_binding() {
+        let dir = temp_dir("exec-on-start");
+        let addr = format!("127.0.0.1:{}", free_port());
+        let path = dir.to_str().unwrap();
+
+        let result = serve(args(&[
+            path,
+            "--address",
+            &addr,
+            "--exec-on-start",
+            "false",
+        ]));
+        assert!(result.unwrap_err().to_string().contains("`false` failed"));
+        assert!(TcpStream::connect(&addr).is_err());
+        assert!(TcpListener::bind(&addr).is_ok());
+    }
+
+    #[test]
+    fn running_out_of_watches_says_what_to_do() {
+        let error = notify::Error::new(notify::ErrorKind::MaxFilesWatch);
+        let message = watch_error_message(Path::new("site"), &error);
+        assert!(
+            message.starts_with("[ERR] While watching site: "),
+            "{message}"
+        );
+        assert!(message.contains("fs.inotify.max_user_watches"), "{message}");
+        assert!(message.contains("--watch-root-only"), "{message}");
+        assert!(message.contains("--poll"), "{message}");
+
+        let error = notify::Error::path_not_found();
+        let message = watch_error_message(Path::new("site"), &error);
+        assert!(!message.contains("--poll"), "{message}");
+    }
+
+    #[test]
+    fn reload_waits_for_the_delay() {
+        let dir = temp_dir("reload-delay");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        let site = Arc::new(server(&dir, &["--reload-delay", "200"]));
+        let mut browser = subscribe(&site);
+        let changes = changes(&site, &dir);
+
+        let changed = Instant::now();
+        changes.send(change(dir.join("index.html"))).unwrap();
+        assert!(next_event(&mut browser).contains("data: update"));
+        assert!(changed.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn changes_within_the_min_interval_reload_once() {
+        let dir = temp_dir("reload-min-interval");
+        fs::write(dir.join("index.html"), "hi").unwrap();
+        fs::write(dir.join("about.html"), "about").unwrap();
+        let site = Arc::new(server(&dir, &["--reload-min-interval", "300"]));
+        let mut browser = subscribe(&site);
+        let changes = changes(&site, &dir);
+
+        changes.send(change(dir.join("index.html"))).unwrap();
+        assert!(next_event(&mut browser).contains("id: 1\n"));
+
+        // both come in while waiting for the interval to pass
+        changes.send(change(dir.join("index.html"))).unwrap();
+        changes.send(change(dir.join("about.html"))).unwrap();
+        assert!(next_event(&mut browser).contains("id: 2\n"));
+
+        browser
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        assert!(browser.read(&mut [0; 64]).is_err());
+        assert_eq!(site.stats.generation.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn reopen_only_when_nobody_got_the_reload() {
+        let open_on_change = args(&["--open-on-change"]);
+        assert!(should_reopen(&open_on_change, 0, None));
+        assert!(!should_reopen(&open_on_change, 1, None));
+
+        // not again right away, but after a while
+        assert!(!should_reopen(&open_on_change, 0, Some(Instant::now())));
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(60));
+        assert!(long_ago.is_none() || should_reopen(&open_on_change, 0, long_ago));
+
+        assert!(!should_reopen(&args(&[]), 0, None));
+    }
 }
@@ -0,0 +1,232 @@
+use std::{fs, io, path::Path, sync::OnceLock};
+
+/// Mime types set with --mime and --mime-file, these win over the built in ones
+static OVERRIDES: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+/// Known file extensions and their mime types
+// see https://developer.mozilla.org/en-US/docs/Web/HTTP/Basics_of_HTTP/MIME_types/Common_types
+pub const MIME_TYPES: &[(&str, &str)] = &[
+    ("aac", "audio/aac"),
+    ("abw", "application/x-abiword"),
+    ("apng", "image/apng"),
+    ("arc", "application/x-freearc"),
+    ("avif", "image/avif"),
+    ("avi", "video/x-msvideo"),
+    ("azw", "application/vnd.amazon.ebook"),
+    ("bin", "application/octet-stream"),
+    ("bmp", "image/bmp"),
+    ("bz", "application/x-bzip"),
+    ("bz2", "application/x-bzip2"),
+    ("cda", "application/x-cdf"),
+    ("csh", "application/x-csh"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("doc", "application/msword"),
+    (
+        "docx",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    ),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("epub", "application/epub+zip"),
+    ("gz", "application/gzip"),
+    ("gif", "application/gif"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("ics", "text/calendar"),
+    ("jar", "application/java-archive"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+    // modules need a javascript type to load, --mime js=application/javascript can change it
+    ("mjs", "text/javascript"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mpeg"),
+    ("mpeg", "video/mpeg"),
+    ("mpkg", "application/vnd.apple.installer+xml"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("oga", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("ogx", "application/ogg"),
+    ("opus", "audio/opus"),
+    ("otf", "font/otf"),
+    ("png", "image/png"),
+    ("pdf", "application/pdf"),
+    ("php", "application/x-httpd-php"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    (
+        "pptx",
+        "application/vnd/openxmlformats-officedocument.presentationml.presentation",
+    ),
+    ("rar", "application/vnd.rar"),
+    ("rtf", "application/rtf"),
+    ("sh", "application/x-sh"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("ts", "video/mp2t"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain"),
+    ("vsd", "application/vnd.visio"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xhtml", "application/xhtml+xml"),
+    ("xls", "application/vnd.ms-exel"),
+    (
+        "xlsx",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    ),
+    ("xml", "application/xml"),
+    ("xul", "application/vnd.mozilla.xul+xml"),
+    ("zip", "application/zip"),
+    ("3pg", "video/3gpp"),
+    ("3g2", "video/3ggp2"),
+    ("7z", "application/x-7z-compressed"),
+    // Missing for some reason
+    ("wasm", "application/wasm"),
+    // Game engine exports, from emscripten, unity and godot. These pair with a .wasm file,
+    // which needs application/wasm above for WebAssembly.instantiateStreaming to work
+    ("data", "application/octet-stream"),
+    ("mem", "application/octet-stream"),
+    ("pck", "application/octet-stream"),
+    ("unityweb", "application/octet-stream"),
+];
+
+/// Get a mime type from a file path
+pub fn get_mime_type<P: AsRef<Path>>(path: &P) -> Option<&'static str> {
+    find_mime_type(overrides(), path.as_ref())
+}
+
+/// Get a mime type from a file path, looking in the overrides before the built in types
+pub fn find_mime_type<'a>(overrides: &'a [(String, String)], path: &Path) -> Option<&'a str> {
+    let extension = path.extension()?.to_str()?;
+
+    mime_types(overrides)
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, mime)| mime)
+}
+
+/// Set the mime types to use over the built in ones, the first one for an extension wins
+///
+/// Can only be done once, before anything is served
+pub fn set_overrides(overrides: Vec<(String, String)>) {
+    OVERRIDES
+        .set(overrides)
+        .expect("Mime overrides were already set");
+}
+
+/// Read an apache style mime.types file, with a mime type and its extensions on each line
+pub fn read_mime_file(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let types = fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(|line| {
+            let mut parts = line.split_whitespace();
+            let mime = parts.next().unwrap_or("");
+            parts.map(move |ext| (ext.to_string(), mime.to_string()))
+        })
+        .collect();
+
+    Ok(types)
+}
+
+/// Mime types set with --mime and --mime-file, none if they weren't set
+fn overrides() -> &'static [(String, String)] {
+    OVERRIDES.get().map_or(&[], Vec::as_slice)
+}
+
+/// All extensions and their mime types, overrides first
+fn mime_types(overrides: &[(String, String)]) -> impl Iterator<Item = (&str, &str)> {
+    overrides
+        .iter()
+        .map(|(ext, mime)| (ext.as_str(), mime.as_str()))
+        .chain(MIME_TYPES.iter().copied())
+}
+
+/// Whether a mime type is text, and should have a charset attached
+///
+/// Json is left out, as it's always utf-8 and has no charset parameter
+pub fn is_text_mime_type(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/javascript"
+                | "application/x-javascript"
+                | "application/ecmascript"
+                | "application/xml"
+                | "application/xhtml+xml"
+                | "image/svg+xml"
+        )
+}
+
+/// Print the mime table with the overrides, for --list-mime
+pub fn print_mime_types() {
+    for line in mime_table(overrides()) {
+        println!("{line}");
+    }
+}
+
+/// Lines of the mime table, with the extensions lined up
+fn mime_table(overrides: &[(String, String)]) -> Vec<String> {
+    // only the type that's used for each extension
+    let mut types = Vec::<(&str, &str)>::new();
+    for (ext, mime) in mime_types(overrides) {
+        if !types.iter().any(|(known, _)| *known == ext) {
+            types.push((ext, mime));
+        }
+    }
+
+    let width = types.iter().map(|(ext, _)| ext.len()).max().unwrap_or(0);
+    types
+        .into_iter()
+        .map(|(ext, mime)| format!("{ext:>width$} -> {mime}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{find_mime_type, mime_table};
+
+    #[test]
+    fn game_engine_files_are_binary() {
+        let mime = |path: &str| find_mime_type(&[], Path::new(path));
+
+        assert_eq!(mime("game.data"), Some("application/octet-stream"));
+        assert_eq!(mime("game.mem"), Some("application/octet-stream"));
+        assert_eq!(mime("game.pck"), Some("application/octet-stream"));
+        assert_eq!(mime("Build.unityweb"), Some("application/octet-stream"));
+    }
+
+    #[test]
+    fn table_has_one_line_per_extension() {
+        let table = mime_table(&[]);
+        assert!(table.iter().any(|line| line.trim() == "css -> text/css"));
+        assert!(table
+            .iter()
+            .all(|line| line.find(" -> ") == table[0].find(" -> ")));
+
+        // an override replaces the built in type instead of adding a line
+        let overrides = [("css".to_string(), "text/x-css".to_string())];
+        let overridden = mime_table(&overrides);
+        assert_eq!(overridden.len(), table.len());
+        assert!(overridden
+            .iter()
+            .any(|line| line.trim() == "css -> text/x-css"));
+        assert!(!overridden
+            .iter()
+            .any(|line| line.trim() == "css -> text/css"));
+    }
+}
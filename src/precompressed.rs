@@ -0,0 +1,130 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Encodings that can be served precompressed and the extension of their files, preferred first
+const ENCODINGS: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+/// Whether there are any precompressed versions of the file next to it
+pub fn has_variants(path: &Path) -> bool {
+    ENCODINGS
+        .iter()
+        .any(|(_, ext)| variant_path(path, ext).is_file())
+}
+
+/// Read the precompressed version of the file the client likes best
+///
+/// Versions are read with `read`, so they follow the same rules as other files.
+/// Returns the content, and the encoding it was compressed with
+pub fn find(
+    path: &Path,
+    accept_encoding: &str,
+    read: &dyn Fn(&Path) -> io::Result<Vec<u8>>,
+) -> Option<(Vec<u8>, &'static str)> {
+    let mut best: Option<(f32, Vec<u8>, &'static str)> = None;
+
+    for (encoding, ext) in ENCODINGS {
+        let quality = quality(accept_encoding, encoding);
+        if quality <= 0.0 || best.as_ref().is_some_and(|(best, _, _)| *best >= quality) {
+            continue;
+        }
+
+        if let Ok(content) = read(&variant_path(path, ext)) {
+            best = Some((quality, content, encoding));
+        }
+    }
+
+    best.map(|(_, content, encoding)| (content, encoding))
+}
+
+/// Path of the file with the extension added on to it
+fn variant_path(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+/// How much the client wants the encoding, from the Accept-Encoding header
+///
+/// 0 means it doesn't accept it at all
+fn quality(accept_encoding: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+        let quality = params
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|q| q.parse().ok())
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality;
+        } else if name == "*" {
+            wildcard = quality;
+        }
+    }
+
+    wildcard
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, ErrorKind},
+        path::Path,
+    };
+
+    use super::{find, quality};
+
+    #[test]
+    fn quality_from_accept_encoding() {
+        assert_eq!(quality("gzip, br", "br"), 1.0);
+        assert_eq!(quality("gzip;q=0.5, br;q=0.8", "gzip"), 0.5);
+        assert_eq!(quality("GZIP", "gzip"), 1.0);
+        assert_eq!(quality("gzip", "br"), 0.0);
+        assert_eq!(quality("", "gzip"), 0.0);
+    }
+
+    #[test]
+    fn wildcard_covers_encodings_not_named() {
+        assert_eq!(quality("*;q=0.3", "br"), 0.3);
+        assert_eq!(quality("br;q=0, *", "br"), 0.0);
+        assert_eq!(quality("*, gzip;q=0.2", "gzip"), 0.2);
+    }
+
+    /// Read only the variants that are in the list
+    fn read_from<'a>(files: &'a [&str]) -> impl Fn(&Path) -> io::Result<Vec<u8>> + 'a {
+        move |path| {
+            let path = path.to_str().unwrap();
+            files
+                .contains(&path)
+                .then(|| path.as_bytes().to_vec())
+                .ok_or_else(|| io::Error::from(ErrorKind::NotFound))
+        }
+    }
+
+    #[test]
+    fn picks_the_preferred_variant() {
+        let both = read_from(&["app.js.br", "app.js.gz"]);
+        let find = |accept| find(Path::new("app.js"), accept, &both).map(|(_, encoding)| encoding);
+
+        // ties go to br, otherwise the higher quality wins
+        assert_eq!(find("gzip, br"), Some("br"));
+        assert_eq!(find("br;q=0.5, gzip"), Some("gzip"));
+        assert_eq!(find("gzip"), Some("gzip"));
+        assert_eq!(find("identity"), None);
+        assert_eq!(find("br;q=0, gzip;q=0"), None);
+    }
+
+    #[test]
+    fn skips_variants_that_cant_be_read() {
+        let gz_only = read_from(&["app.js.gz"]);
+        let (content, encoding) = find(Path::new("app.js"), "br, gzip", &gz_only).unwrap();
+
+        assert_eq!(encoding, "gzip");
+        assert_eq!(content, b"app.js.gz");
+    }
+}
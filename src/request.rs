@@ -0,0 +1,281 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind, Read},
+};
+
+/// Room for the headers in a request head, on top of the longest url that is allowed
+const MAX_HEADERS_SIZE: usize = 64 * 1024;
+
+/// Parsed request line and headers
+pub struct Request {
+    /// Request method, like GET
+    pub method: String,
+
+    /// Path part of the request target
+    pub path: String,
+
+    /// Query part of the request target, without the ?
+    pub query: Option<String>,
+
+    /// Http version, like HTTP/1.1
+    pub version: String,
+
+    /// Headers, in the order they were sent
+    pub headers: Vec<(String, String)>,
+
+    /// Request head as it was received
+    pub raw: Vec<u8>,
+}
+
+/// Error for a request head over the size limit, inside an `InvalidData` io error
+#[derive(Debug)]
+pub struct TooLarge {
+    /// Whether the request line alone was already too long
+    pub line_too_long: bool,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line_too_long {
+            write!(f, "request line is too long")
+        } else {
+            write!(f, "request head is too large")
+        }
+    }
+}
+
+impl Error for TooLarge {}
+
+impl TooLarge {
+    /// Get it back out of the io error, if that's what the error was
+    pub fn from_io(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl Request {
+    /// Read a request from the stream
+    ///
+    /// This keeps reading until the full head is in, as clients can send it in multiple parts.
+    /// The head can be as long as the longest url plus room for the headers.
+    /// Returns None if the client didn't send anything
+    pub fn read<R: Read>(stream: &mut R, max_uri_length: usize) -> io::Result<Option<Self>> {
+        let max_head_size = max_uri_length.saturating_add(MAX_HEADERS_SIZE);
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+
+        while !is_complete(&head) {
+            let len = match stream.read(&mut buf) {
+                // client is done sending
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // client opened the connection but never sent anything
+                Err(e)
+                    if head.is_empty()
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            };
+
+            head.extend_from_slice(&buf[..len]);
+
+            if head.len() > max_head_size {
+                let line_too_long = !head.contains(&b'\n');
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    TooLarge { line_too_long },
+                ));
+            }
+        }
+
+        Ok(Self::parse(head))
+    }
+
+    /// Parse a request head
+    fn parse(raw: Vec<u8>) -> Option<Self> {
+        let head = String::from_utf8_lossy(&raw).into_owned();
+        let mut lines = head.lines();
+
+        // request line, clients can put more than one space between the parts
+        let request_line = lines.next().filter(|line| !line.trim().is_empty())?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("").to_string();
+        let target = parts.next().unwrap_or("");
+        let version = parts.next().unwrap_or("").to_string();
+
+        // split off the query
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+
+        // headers, up to the empty line
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        Some(Self {
+            method,
+            path,
+            query,
+            version,
+            headers,
+            raw,
+        })
+    }
+
+    /// Lines of the raw head with anything unprintable escaped, and credentials left out
+    pub fn trace_lines(&self) -> Vec<String> {
+        self.raw
+            .split(|&byte| byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .take_while(|line| !line.is_empty())
+            .map(|line| {
+                let name =
+                    String::from_utf8_lossy(line.split(|&byte| byte == b':').next().unwrap_or(b""));
+                if ["Authorization", "Proxy-Authorization"]
+                    .iter()
+                    .any(|secret| name.trim().eq_ignore_ascii_case(secret))
+                {
+                    format!("{name}: [redacted]")
+                } else {
+                    line.escape_ascii().to_string()
+                }
+            })
+            .collect()
+    }
+
+    /// Get a header by name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get a query parameter by name
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .map(|param| param.split_once('=').unwrap_or((param, "")))
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Whether the head contains the empty line that ends it
+fn is_complete(head: &[u8]) -> bool {
+    head.windows(4).any(|w| w == b"\r\n\r\n") || head.windows(2).any(|w| w == b"\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind, Read};
+
+    use super::{Request, TooLarge};
+
+    /// Reader that hands out the parts one read at a time, like a client sending them separately
+    struct Parts(Vec<Vec<u8>>);
+
+    impl Read for Parts {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::from(ErrorKind::WouldBlock));
+            }
+            let part = self.0.remove(0);
+            buf[..part.len()].copy_from_slice(&part);
+            Ok(part.len())
+        }
+    }
+
+    fn parts(parts: &[&str]) -> Parts {
+        Parts(parts.iter().map(|part| part.as_bytes().to_vec()).collect())
+    }
+
+    #[test]
+    fn reads_a_head_sent_in_parts() {
+        let mut stream = parts(&[
+            "GET /docs/page.html?a=1&b HTTP/1.1\r\nHo",
+            "st: localhost\r\nAccept: text/html\r\n",
+            "\r\n",
+        ]);
+        let request = Request::read(&mut stream, 8192).unwrap().unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/docs/page.html");
+        assert_eq!(request.query.as_deref(), Some("a=1&b"));
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.header("ACCEPT"), Some("text/html"));
+        assert_eq!(request.query_param("a"), Some("1"));
+        assert_eq!(request.query_param("b"), Some(""));
+        assert_eq!(request.query_param("c"), None);
+    }
+
+    #[test]
+    fn nothing_sent_is_no_request() {
+        assert!(Request::read(&mut parts(&[]), 8192).unwrap().is_none());
+        assert!(Request::read(&mut parts(&["\r\n\r\n"]), 8192)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn heads_over_the_limit_are_too_large() {
+        let long_line = format!("GET /{} HTTP/1.1", "a".repeat(100 * 1024));
+        let error = Request::read(&mut long_line.as_bytes(), 8192)
+            .err()
+            .unwrap();
+        assert!(TooLarge::from_io(&error).unwrap().line_too_long);
+
+        let long_header = format!("GET / HTTP/1.1\r\nX-Big: {}", "a".repeat(100 * 1024));
+        let error = Request::read(&mut long_header.as_bytes(), 8192)
+            .err()
+            .unwrap();
+        assert!(!TooLarge::from_io(&error).unwrap().line_too_long);
+    }
+
+    #[test]
+    fn head_limit_grows_with_the_url_limit() {
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(100 * 1024));
+        let request = Request::read(&mut long_line.as_bytes(), 200 * 1024)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.path.len(), 100 * 1024 + 1);
+    }
+
+    #[test]
+    fn request_line_can_have_extra_whitespace() {
+        let mut head = "GET  \t/page.html   HTTP/1.1 \r\nHost:   localhost  \r\n\r\n".as_bytes();
+        let request = Request::read(&mut head, 8192).unwrap().unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/page.html");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("Host"), Some("localhost"));
+    }
+
+    #[test]
+    fn trace_leaves_out_credentials() {
+        let mut head = "GET /admin HTTP/1.1\r\nHost: localhost\r\nauthorization: Basic c2VjcmV0\r\nProxy-Authorization: Bearer token\r\nX-Odd: \x01\r\n\r\n".as_bytes();
+        let request = Request::read(&mut head, 8192).unwrap().unwrap();
+
+        assert_eq!(
+            request.trace_lines(),
+            [
+                "GET /admin HTTP/1.1",
+                "Host: localhost",
+                "authorization: [redacted]",
+                "Proxy-Authorization: [redacted]",
+                "X-Odd: \\x01",
+            ]
+        );
+    }
+}
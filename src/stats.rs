@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of what the server did
+#[derive(Default)]
+pub struct Stats {
+    /// Connections accepted
+    pub connections: AtomicU64,
+
+    /// Requests read
+    pub requests: AtomicU64,
+
+    /// Bytes sent in responses
+    pub bytes_sent: AtomicU64,
+
+    /// Reloads sent to browsers, so they can tell when they missed one
+    pub generation: AtomicU64,
+}
+
+impl Stats {
+    /// Human readable summary, for when the server stops
+    pub fn summary(&self) -> String {
+        format!(
+            "served {} requests over {} connections, sent {} bytes",
+            self.requests.load(Ordering::Relaxed),
+            self.connections.load(Ordering::Relaxed),
+            self.bytes_sent.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Json for the status page, with the number of connected reload clients
+    pub fn to_json(&self, reload_clients: usize) -> String {
+        format!(
+            "{{\"connections\":{},\"requests\":{},\"bytes_sent\":{},\"reload_clients\":{},\"generation\":{}}}",
+            self.connections.load(Ordering::Relaxed),
+            self.requests.load(Ordering::Relaxed),
+            self.bytes_sent.load(Ordering::Relaxed),
+            reload_clients,
+            self.generation.load(Ordering::Relaxed),
+        )
+    }
+}
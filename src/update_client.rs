@@ -0,0 +1,174 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+use crate::chunked::ChunkedWriter;
+
+/// Most bytes to keep around for a client that isn't reading
+const MAX_PENDING: usize = 64 * 1024;
+
+/// Browser connected to the event stream, to notify of updates
+///
+/// The stream is non-blocking, so a slow client can't hold up the others.
+/// Whatever couldn't be written yet is kept, and sent with the next event
+pub struct UpdateClient {
+    stream: TcpStream,
+    pending: Vec<u8>,
+}
+
+impl UpdateClient {
+    /// Make a client from a stream that already had the event stream response head sent
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Address of the browser, if the connection is still there
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr().ok()
+    }
+
+    /// Send an event, errors mean the client is gone
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        ChunkedWriter::new(&mut self.pending).write_all(data)?;
+        self.write_pending()
+    }
+
+    /// Whether the client closed the connection, without waiting for the next event
+    pub fn is_closed(&mut self) -> bool {
+        let mut buf = [0; 256];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return true,
+                // browsers don't send anything on the event stream, so ignore it
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    return self.write_pending().is_err()
+                }
+                Err(_) => return true,
+            }
+        }
+    }
+
+    /// End the event stream
+    pub fn finish(mut self) -> io::Result<()> {
+        ChunkedWriter::new(&mut self.pending).finish()?;
+        self.write_pending()
+    }
+
+    /// Write as much of the pending data as the client accepts right now
+    fn write_pending(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.pending.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                // buffer is full, try again on the next event
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        // client stopped reading a long time ago
+        if self.pending.len() > MAX_PENDING {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "client is not reading events",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    use socket2::{Domain, SockRef, Socket, Type};
+
+    use super::{UpdateClient, MAX_PENDING};
+    use crate::chunked::ChunkedWriter;
+
+    /// Client and the browser side of its connection, both with tiny buffers so they fill up fast
+    fn pair() -> (UpdateClient, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket.set_recv_buffer_size(1024).unwrap();
+        socket
+            .connect(&listener.local_addr().unwrap().into())
+            .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        SockRef::from(&stream).set_send_buffer_size(1024).unwrap();
+        (UpdateClient::new(stream).unwrap(), socket.into())
+    }
+
+    /// Everything the browser can read right now
+    fn read_available(browser: &mut TcpStream) -> Vec<u8> {
+        browser
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match browser.read(&mut buf) {
+                Ok(0) => return received,
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return received,
+                Err(e) if e.kind() == ErrorKind::TimedOut => return received,
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    #[test]
+    fn full_buffer_keeps_the_client_and_sends_the_rest_later() {
+        let (mut client, mut browser) = pair();
+        let event = [b'a'; 1000];
+        let mut sent = Vec::new();
+
+        // fill the buffers until some of it has to wait
+        while client.pending.is_empty() {
+            client.send(&event).unwrap();
+            ChunkedWriter::new(&mut sent).write_all(&event).unwrap();
+        }
+        assert!(client.pending.len() <= MAX_PENDING);
+        assert!(!client.is_closed());
+
+        // once the browser reads, the next event takes what was left along
+        let mut received = read_available(&mut browser);
+        client.send(b"last").unwrap();
+        ChunkedWriter::new(&mut sent).write_all(b"last").unwrap();
+        assert!(client.pending.is_empty());
+
+        received.extend(read_available(&mut browser));
+        assert_eq!(received.len(), sent.len());
+        assert!(received == sent);
+    }
+
+    #[test]
+    fn client_that_stopped_reading_is_dropped() {
+        let (mut client, _browser) = pair();
+        let event = [b'a'; 1000];
+
+        let error = loop {
+            if let Err(e) = client.send(&event) {
+                break e;
+            }
+        };
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(client.pending.len() > MAX_PENDING);
+    }
+}